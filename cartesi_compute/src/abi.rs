@@ -0,0 +1,360 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Declarative mapping between contract ABI values and Rust structs.
+//!
+//! `abi_tuple!` declares a struct decoded from an ABI tuple, listing the
+//! name and type of each component in order. `abi_outputs!` declares a
+//! struct decoded from the outputs of a view function, listed the same
+//! way. Both are checked against the deployed ABI at startup with
+//! `check_function_outputs`, and the node doesn't start if they don't
+//! match.
+
+use super::error::*;
+use super::ethabi::{self, Token};
+use super::ethereum_types::{Address, H256, U256};
+use super::serde_json;
+
+/// Representation of a single value returned by the dispatcher
+#[derive(Serialize, Deserialize)]
+pub struct AbiField<T> {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub value: T,
+}
+
+/// Rust types that stand for an ABI type
+pub trait AbiType {
+    /// Canonical type, as reported by the dispatcher
+    fn abi_type() -> String;
+
+    /// Names and canonical types of the components of a tuple type
+    fn components() -> Vec<(&'static str, String)> {
+        vec![]
+    }
+}
+
+impl AbiType for Vec<U256> {
+    fn abi_type() -> String {
+        "uint256[]".into()
+    }
+}
+
+impl AbiType for Vec<Address> {
+    fn abi_type() -> String {
+        "address[]".into()
+    }
+}
+
+impl AbiType for Vec<H256> {
+    fn abi_type() -> String {
+        "bytes32[]".into()
+    }
+}
+
+impl AbiType for Vec<u8> {
+    fn abi_type() -> String {
+        "bytes".into()
+    }
+}
+
+impl AbiType for bool {
+    fn abi_type() -> String {
+        "bool".into()
+    }
+}
+
+/// Declare a struct decoded from an ABI tuple, with its components
+/// listed in the order the contract declares them.
+///
+/// The dispatcher hands tuples over as bare arrays of values, so the
+/// components are decoded by position and their names are not seen at
+/// decoding time. Only the startup check against the ABI, which compares
+/// the name and type of every component in order, catches a reordering.
+#[macro_export]
+macro_rules! abi_tuple {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $($field:ident: $ty:ty = ($abi_name:expr, $abi_type:expr)),+ $(,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Serialize, Debug)]
+        pub struct $name {
+            $(pub $field: $ty),+
+        }
+
+        impl $crate::abi::AbiType for $name {
+            fn abi_type() -> String {
+                let types: Vec<&str> = vec![$($abi_type),+];
+                format!("({})", types.join(","))
+            }
+
+            fn components() -> Vec<(&'static str, String)> {
+                vec![$(($abi_name, $abi_type.to_string())),+]
+            }
+        }
+
+        impl $crate::abi::AbiType for Vec<$name> {
+            fn abi_type() -> String {
+                format!("{}[]", <$name as $crate::abi::AbiType>::abi_type())
+            }
+
+            fn components() -> Vec<(&'static str, String)> {
+                <$name as $crate::abi::AbiType>::components()
+            }
+        }
+
+        impl<'de> ::serde::Deserialize<'de> for $name {
+            fn deserialize<D>(
+                deserializer: D,
+            ) -> ::std::result::Result<Self, D::Error>
+            where
+                D: ::serde::Deserializer<'de>,
+            {
+                #[derive(Deserialize)]
+                struct Positional($($ty),+);
+
+                let Positional($($field),+) =
+                    Positional::deserialize(deserializer)?;
+                Ok($name { $($field: $field),+ })
+            }
+        }
+    };
+}
+
+/// Declare a struct decoded from the outputs of a contract function,
+/// with the outputs listed in the order the contract declares them.
+///
+/// Outputs are decoded by position and checked against their ABI type.
+/// Unnamed outputs are declared with an empty name, as the deployed
+/// contracts leave some of them unnamed.
+#[macro_export]
+macro_rules! abi_outputs {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $($field:ident: $ty:ty = $abi_name:expr; $wrapper:ty),+ $(,)*
+        }
+    ) => {
+        $(#[$meta])*
+        pub struct $name {
+            $(pub $field: $ty),+
+        }
+
+        impl $name {
+            /// Names, canonical types and components of the outputs
+            pub fn outputs(
+            ) -> Vec<(&'static str, String, Vec<(&'static str, String)>)> {
+                vec![$((
+                    $abi_name,
+                    <$ty as $crate::abi::AbiType>::abi_type(),
+                    <$ty as $crate::abi::AbiType>::components(),
+                )),+]
+            }
+        }
+
+        impl<'de> ::serde::Deserialize<'de> for $name {
+            fn deserialize<D>(
+                deserializer: D,
+            ) -> ::std::result::Result<Self, D::Error>
+            where
+                D: ::serde::Deserializer<'de>,
+            {
+                use ::serde::de::Error;
+
+                let entries: Vec<::serde_json::Value> =
+                    ::serde::Deserialize::deserialize(deserializer)?;
+                let mut entries = entries.iter();
+
+                $(
+                    let $field: $ty = {
+                        let entry = entries.next().ok_or(
+                            D::Error::custom(format!(
+                                "missing output {}",
+                                stringify!($field)
+                            )),
+                        )?;
+                        let abi_type =
+                            <$ty as $crate::abi::AbiType>::abi_type();
+                        if entry["type"] != abi_type.as_str() {
+                            return Err(D::Error::custom(format!(
+                                "output {} has type {}, expected {}",
+                                stringify!($field), entry["type"], abi_type
+                            )));
+                        }
+                        let wrapped: $wrapper =
+                            ::serde_json::from_value(entry.clone())
+                                .map_err(D::Error::custom)?;
+                        wrapped.value
+                    };
+                )+
+
+                Ok($name { $($field: $field),+ })
+            }
+        }
+    };
+}
+
+/// Check the outputs of `function` in the contract ABI stored at
+/// `abi_path` against the ones a struct declared with `abi_outputs!`
+/// expects
+pub fn check_function_outputs(
+    abi_path: &str,
+    function: &str,
+    outputs: Vec<(&'static str, String, Vec<(&'static str, String)>)>,
+) -> Result<()> {
+    let abi_outputs = get_function(abi_path, function)?["outputs"]
+        .as_array()
+        .map(|o| o.clone())
+        .unwrap_or(vec![]);
+    if abi_outputs.len() != outputs.len() {
+        return Err(format!(
+            "{} has {} outputs in contract abi {}, expected {}",
+            function,
+            abi_outputs.len(),
+            abi_path,
+            outputs.len()
+        )
+        .into());
+    }
+
+    for (position, (output, (name, ty, components))) in
+        abi_outputs.iter().zip(outputs).enumerate()
+    {
+        if output["name"] != name {
+            return Err(format!(
+                "Output {} of {} is named {} in contract abi {}, expected {:?}",
+                position, function, output["name"], abi_path, name
+            )
+            .into());
+        }
+
+        let abi_type = canonical_type(output);
+        if abi_type != ty {
+            return Err(format!(
+                "Output {} of {} has type {} in contract abi {}, expected {}",
+                position, function, abi_type, abi_path, ty
+            )
+            .into());
+        }
+
+        let abi_components = output["components"]
+            .as_array()
+            .map(|c| c.clone())
+            .unwrap_or(vec![]);
+        let matches = abi_components.len() == components.len()
+            && abi_components.iter().zip(components.iter()).all(
+                |(abi_component, (name, ty))| {
+                    abi_component["name"] == *name
+                        && canonical_type(abi_component) == *ty
+                },
+            );
+        if !matches {
+            return Err(format!(
+                "Components of output {} of {} in contract abi {} don't match {:?}",
+                position, function, abi_path, components
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Names of the inputs of `function` in the contract ABI stored at
+/// `abi_path`, in order
+pub fn get_function_inputs(
+    abi_path: &str,
+    function: &str,
+) -> Result<Vec<String>> {
+    Ok(get_function(abi_path, function)?["inputs"]
+        .as_array()
+        .map(|inputs| {
+            inputs
+                .iter()
+                .map(|input| input["name"].as_str().unwrap_or("").into())
+                .collect()
+        })
+        .unwrap_or(vec![]))
+}
+
+/// Call data of `function` in the contract ABI stored at `abi_path`
+/// with arguments `data`
+pub fn encode_call(
+    abi_path: &str,
+    function: &str,
+    data: &[Token],
+) -> Result<Vec<u8>> {
+    let entry = get_function(abi_path, function)?;
+    let entry: ethabi::Function =
+        serde_json::from_value(entry).chain_err(|| {
+            format!("Could not parse {} in contract abi {}", function, abi_path)
+        })?;
+    entry.encode_input(data).map_err(|e| {
+        Error::from(format!("Could not encode {} call: {:?}", function, e))
+    })
+}
+
+/// Whether the contract ABI stored at `abi_path` has `function`
+pub fn has_function(abi_path: &str, function: &str) -> bool {
+    get_function(abi_path, function).is_ok()
+}
+
+/// Entry of `function` in the contract ABI stored at `abi_path`
+fn get_function(abi_path: &str, function: &str) -> Result<serde_json::Value> {
+    let file = std::fs::File::open(abi_path)
+        .chain_err(|| format!("Could not open contract abi {}", abi_path))?;
+    let json: serde_json::Value = serde_json::from_reader(file)
+        .chain_err(|| format!("Could not parse contract abi {}", abi_path))?;
+
+    // deployment files wrap the abi together with the address
+    let abi = json.get("abi").unwrap_or(&json);
+    abi.as_array()
+        .and_then(|entries| {
+            entries.iter().find(|entry| {
+                entry["type"] == "function" && entry["name"] == function
+            })
+        })
+        .cloned()
+        .ok_or(Error::from(format!(
+            "Function {} not found in contract abi {}",
+            function, abi_path
+        )))
+}
+
+/// Type of an abi entry, with tuples spelled out as their components
+fn canonical_type(entry: &serde_json::Value) -> String {
+    let ty = entry["type"].as_str().unwrap_or("");
+    if !ty.starts_with("tuple") {
+        return ty.to_string();
+    }
+
+    let components: Vec<String> = entry["components"]
+        .as_array()
+        .map(|c| c.iter().map(canonical_type).collect())
+        .unwrap_or(vec![]);
+    format!("({}){}", components.join(","), &ty["tuple".len()..])
+}
@@ -0,0 +1,698 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! A minimal reader for CARv1 archives holding a single UnixFS file,
+//! used to unpack drives published with the downloadAsCAR flag. Blocks
+//! are checked against the sha2-256 multihash of their CID as they are
+//! read, and dag-pb nodes are decoded with protobuf.
+
+use super::error::*;
+use super::hex;
+use super::protobuf::wire_format::WireType;
+use super::protobuf::{CodedInputStream, ProtobufResult};
+use super::sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+const CODEC_RAW: u64 = 0x55;
+const CODEC_DAG_PB: u64 = 0x70;
+const CBOR_TAG_CID: u64 = 42;
+const MULTIHASH_SHA2_256: u64 = 0x12;
+const UNIXFS_RAW: u64 = 0;
+const UNIXFS_FILE: u64 = 2;
+const SHA2_256_SIZE: usize = 32;
+
+/// Largest header and block taken from an archive, well over what the
+/// ipfs service writes
+const MAX_HEADER_SIZE: u64 = 1 << 16;
+const MAX_BLOCK_SIZE: u64 = 1 << 22;
+/// Deepest file DAG and CBOR header nesting followed
+const MAX_DAG_DEPTH: usize = 64;
+const MAX_CBOR_DEPTH: usize = 16;
+/// Bytes an archive may take besides the file it holds, for its header
+/// and the CIDs and links of blocks holding at least 1 KiB of it each
+const ARCHIVE_OVERHEAD: u64 = 1024;
+
+/// Log2 size bounding the CAR archive of a drive of `2^log2_size` bytes
+pub fn get_archive_log2_size(log2_size: u32) -> u32 {
+    let size = 1u64 << log2_size;
+    let archive_size = size
+        .saturating_add(size / 16)
+        .saturating_add(ARCHIVE_OVERHEAD);
+    64 - (archive_size - 1).leading_zeros()
+}
+
+/// Unpack the file stored in the CAR archive at `car_path` into
+/// `output_path`, zero padded to `2^log2_size` bytes
+pub fn unpack_car_file(
+    car_path: &str,
+    output_path: &str,
+    log2_size: u64,
+) -> Result<()> {
+    let car = File::open(car_path)
+        .chain_err(|| format!("Could not open CAR archive {}", car_path))?;
+    let output = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)?;
+
+    let size = 1 << log2_size;
+    let mut writer = BufWriter::new(output);
+    unpack_car(&mut BufReader::new(car), &mut writer, size)
+        .chain_err(|| format!("Could not unpack CAR archive {}", car_path))?;
+    let output = writer.into_inner().map_err(|e| {
+        Error::from(format!("Could not write {}: {}", output_path, e))
+    })?;
+    output.set_len(size)?;
+    Ok(())
+}
+
+/// Write the content of the file rooted at the first root of the archive
+/// to `output`, failing once it takes more than `max_size` bytes, and
+/// return its size. Only the location of each block is kept, blocks are
+/// read again as the file is written.
+pub fn unpack_car<R: Read + Seek>(
+    car: &mut R,
+    output: &mut dyn Write,
+    max_size: u64,
+) -> Result<u64> {
+    let mut pos = 0;
+    let header_len = read_stream_varint(car, &mut pos)?
+        .ok_or(Error::from("Empty CAR archive"))?;
+    if header_len > MAX_HEADER_SIZE {
+        return Err(format!("CAR header of {} bytes", header_len).into());
+    }
+    let header = read_stream_bytes(car, &mut pos, header_len)?;
+    let root = get_root(&read_cbor(&mut Reader::new(&header), 0)?)?;
+
+    let mut blocks = HashMap::new();
+    loop {
+        let section_len = match read_stream_varint(car, &mut pos)? {
+            Some(len) => len,
+            None => break,
+        };
+        // the ipfs service may zero pad the archive
+        if section_len == 0 {
+            break;
+        }
+        if section_len > MAX_BLOCK_SIZE {
+            return Err(format!("CAR block of {} bytes", section_len).into());
+        }
+        let start = pos;
+        let section = read_stream_bytes(car, &mut pos, section_len)?;
+        let mut section = Reader::new(&section);
+        let (cid, codec, digest) = read_cid(&mut section)?;
+        blocks.insert(
+            cid,
+            Block {
+                codec: codec,
+                digest: digest,
+                offset: start + section.pos as u64,
+                len: (section.data.len() - section.pos) as u64,
+            },
+        );
+    }
+
+    let mut unpacker = Unpacker {
+        car: car,
+        blocks: blocks,
+        output: output,
+        size: 0,
+        max_size: max_size,
+    };
+    unpacker.append_file(&root, 0)?;
+    Ok(unpacker.size)
+}
+
+/// Where the data of a block is in the archive
+struct Block {
+    codec: u64,
+    /// Sha2-256 digest the data must have
+    digest: Vec<u8>,
+    offset: u64,
+    len: u64,
+}
+
+struct Unpacker<'a, R> {
+    car: &'a mut R,
+    blocks: HashMap<Vec<u8>, Block>,
+    output: &'a mut dyn Write,
+    size: u64,
+    max_size: u64,
+}
+
+impl<'a, R: Read + Seek> Unpacker<'a, R> {
+    fn append_file(&mut self, cid: &[u8], depth: usize) -> Result<()> {
+        if depth > MAX_DAG_DEPTH {
+            return Err(format!(
+                "CAR file deeper than {} blocks",
+                MAX_DAG_DEPTH
+            )
+            .into());
+        }
+        let (codec, data) = self.read_block(cid)?;
+        let size = self.size;
+        let links = match codec {
+            CODEC_RAW => {
+                self.append_data(&data)?;
+                vec![]
+            }
+            CODEC_DAG_PB => self.append_node(cid, &data)?,
+            _ => {
+                return Err(
+                    format!("Unsupported CAR block codec {:x}", codec).into()
+                )
+            }
+        };
+        // a block below the root adding nothing could be linked without
+        // bound
+        if depth > 0 && links.is_empty() && self.size == size {
+            return Err(
+                format!("CAR block {} is empty", hex::encode(cid)).into()
+            );
+        }
+
+        // blocks may be linked many times, as files with repeated chunks
+        // are, but never in a cycle, as a block would have to hash to
+        // a CID linked from its own data
+        for link in links {
+            self.append_file(&link, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Append the data of a dag-pb node, which comes before the data of
+    /// its children, and return the links to them
+    fn append_node(&mut self, cid: &[u8], data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let node = read_node(data).chain_err(|| get_node_error(cid))?;
+        if let Some(unixfs) = node.unixfs {
+            let (ty, data) =
+                read_unixfs(&unixfs).chain_err(|| get_node_error(cid))?;
+            if ty != UNIXFS_RAW && ty != UNIXFS_FILE {
+                return Err(format!(
+                    "CAR node {} is not a file (type {})",
+                    hex::encode(cid),
+                    ty
+                )
+                .into());
+            }
+            self.append_data(&data)?;
+        }
+        Ok(node.links)
+    }
+
+    fn append_data(&mut self, data: &[u8]) -> Result<()> {
+        self.size += data.len() as u64;
+        if self.size > self.max_size {
+            return Err(format!(
+                "CAR content exceeds {} bytes",
+                self.max_size
+            )
+            .into());
+        }
+        self.output.write_all(data)?;
+        Ok(())
+    }
+
+    /// Codec and data of the block of `cid`, failing unless the data
+    /// hashes to the digest in the CID
+    fn read_block(&mut self, cid: &[u8]) -> Result<(u64, Vec<u8>)> {
+        let (codec, offset, len) = match self.blocks.get(cid) {
+            Some(block) => (block.codec, block.offset, block.len),
+            None => {
+                return Err(format!(
+                    "CAR block {} not found",
+                    hex::encode(cid)
+                )
+                .into())
+            }
+        };
+        self.car.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; len as usize];
+        self.car.read_exact(&mut data)?;
+        if Sha256::digest(&data).as_slice() != &self.blocks[cid].digest[..] {
+            return Err(format!(
+                "CAR block {} does not match its CID",
+                hex::encode(cid)
+            )
+            .into());
+        }
+        Ok((codec, data))
+    }
+}
+
+/// Varint at the current position of the archive, or `None` at its end
+fn read_stream_varint(
+    car: &mut dyn Read,
+    pos: &mut u64,
+) -> Result<Option<u64>> {
+    let mut value = 0u64;
+    let mut byte = [0u8];
+    for shift in (0..64).step_by(7) {
+        if car.read(&mut byte)? == 0 {
+            if shift == 0 {
+                return Ok(None);
+            }
+            return Err("Unexpected end of CAR data".into());
+        }
+        *pos += 1;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err("Varint overflow in CAR data".into())
+}
+
+fn read_stream_bytes(
+    car: &mut dyn Read,
+    pos: &mut u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len as usize];
+    car.read_exact(&mut bytes).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => {
+            Error::from("Unexpected end of CAR data")
+        }
+        _ => e.into(),
+    })?;
+    *pos += len;
+    Ok(bytes)
+}
+
+fn get_root(header: &Cbor) -> Result<Vec<u8>> {
+    if let Cbor::Map(entries) = header {
+        for (key, value) in entries {
+            match (key, value) {
+                (Cbor::Text(k), Cbor::Array(roots)) if k == "roots" => {
+                    if let Some(Cbor::Tag(CBOR_TAG_CID, cid)) = roots.get(0) {
+                        if let Cbor::Bytes(cid) = &**cid {
+                            // skip the identity multibase prefix
+                            if cid.len() > 1 && cid[0] == 0 {
+                                return Ok(cid[1..].to_vec());
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Err("CAR header has no valid root".into())
+}
+
+/// Bytes, codec and sha2-256 digest of the CID at the reader
+fn read_cid(reader: &mut Reader) -> Result<(Vec<u8>, u64, Vec<u8>)> {
+    let start = reader.pos;
+    // CIDv0 is a bare sha2-256 multihash of a dag-pb node
+    let codec = if reader.rest().starts_with(&[0x12, 0x20]) {
+        CODEC_DAG_PB
+    } else {
+        let version = reader.read_varint()?;
+        if version != 1 {
+            return Err(format!("Unsupported CID version {}", version).into());
+        }
+        reader.read_varint()?
+    };
+
+    let hash_code = reader.read_varint()?;
+    let hash_len = reader.read_varint()? as usize;
+    if hash_code != MULTIHASH_SHA2_256 || hash_len != SHA2_256_SIZE {
+        return Err(format!(
+            "Unsupported CID hash {:x} of {} bytes",
+            hash_code, hash_len
+        )
+        .into());
+    }
+    let digest = reader.read_bytes(hash_len)?.to_vec();
+    Ok((reader.data[start..reader.pos].to_vec(), codec, digest))
+}
+
+struct PbNode {
+    unixfs: Option<Vec<u8>>,
+    /// Hashes of the links, the CIDs of the blocks linked
+    links: Vec<Vec<u8>>,
+}
+
+fn read_node(data: &[u8]) -> ProtobufResult<PbNode> {
+    let mut unixfs = None;
+    let mut links = vec![];
+    let mut node = CodedInputStream::from_bytes(data);
+    while !node.eof()? {
+        match node.read_tag_unpack()? {
+            (1, WireType::WireTypeLengthDelimited) => {
+                unixfs = Some(node.read_bytes()?)
+            }
+            (2, WireType::WireTypeLengthDelimited) => {
+                let link = node.read_bytes()?;
+                let mut link = CodedInputStream::from_bytes(&link);
+                while !link.eof()? {
+                    match link.read_tag_unpack()? {
+                        (1, WireType::WireTypeLengthDelimited) => {
+                            links.push(link.read_bytes()?)
+                        }
+                        (_, wire_type) => link.skip_field(wire_type)?,
+                    }
+                }
+            }
+            (_, wire_type) => node.skip_field(wire_type)?,
+        }
+    }
+    Ok(PbNode {
+        unixfs: unixfs,
+        links: links,
+    })
+}
+
+/// Type and content of a UnixFS message
+fn read_unixfs(data: &[u8]) -> ProtobufResult<(u64, Vec<u8>)> {
+    let mut ty = UNIXFS_FILE;
+    let mut content = vec![];
+    let mut unixfs = CodedInputStream::from_bytes(data);
+    while !unixfs.eof()? {
+        match unixfs.read_tag_unpack()? {
+            (1, WireType::WireTypeVarint) => ty = unixfs.read_uint64()?,
+            (2, WireType::WireTypeLengthDelimited) => {
+                content = unixfs.read_bytes()?
+            }
+            (_, wire_type) => unixfs.skip_field(wire_type)?,
+        }
+    }
+    Ok((ty, content))
+}
+
+fn get_node_error(cid: &[u8]) -> String {
+    format!("Invalid dag-pb node in CAR block {}", hex::encode(cid))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data: data, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err("Unexpected end of CAR data".into());
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Varint overflow in CAR data".into())
+    }
+}
+
+enum Cbor {
+    Uint(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    Tag(u64, Box<Cbor>),
+    Simple(u64),
+}
+
+fn read_cbor(reader: &mut Reader, depth: usize) -> Result<Cbor> {
+    if depth > MAX_CBOR_DEPTH {
+        return Err("CAR header nested too deep".into());
+    }
+    let initial = reader.read_u8()?;
+    let major = initial >> 5;
+    let arg = match initial & 0x1f {
+        info if info < 24 => info as u64,
+        24 => reader.read_u8()? as u64,
+        25 => reader.read_bytes(2)?.iter().fold(0, |a, &b| a << 8 | b as u64),
+        26 => reader.read_bytes(4)?.iter().fold(0, |a, &b| a << 8 | b as u64),
+        27 => reader.read_bytes(8)?.iter().fold(0, |a, &b| a << 8 | b as u64),
+        _ => return Err("Unsupported CBOR length in CAR header".into()),
+    };
+
+    match major {
+        0 => Ok(Cbor::Uint(arg)),
+        2 => Ok(Cbor::Bytes(reader.read_bytes(arg as usize)?.to_vec())),
+        3 => Ok(Cbor::Text(
+            String::from_utf8_lossy(reader.read_bytes(arg as usize)?)
+                .into_owned(),
+        )),
+        4 => {
+            let mut items = vec![];
+            for _ in 0..arg {
+                items.push(read_cbor(reader, depth + 1)?);
+            }
+            Ok(Cbor::Array(items))
+        }
+        5 => {
+            let mut entries = vec![];
+            for _ in 0..arg {
+                let key = read_cbor(reader, depth + 1)?;
+                entries.push((key, read_cbor(reader, depth + 1)?));
+            }
+            Ok(Cbor::Map(entries))
+        }
+        6 => Ok(Cbor::Tag(arg, Box::new(read_cbor(reader, depth + 1)?))),
+        7 => Ok(Cbor::Simple(arg)),
+        _ => Err(format!("Unsupported CBOR major type {}", major).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::slice;
+
+    // files in the two layouts ipfs add produces: raw leaves under a
+    // CIDv1 root, one chunk repeated, and dag-pb leaves with CIDv0
+    const RAW_LEAVES: &[u8] =
+        include_bytes!("../tests/fixtures/raw_leaves.car");
+    const RAW_LEAVES_FILE: &[u8] =
+        include_bytes!("../tests/fixtures/raw_leaves.bin");
+    const DAG_PB_LEAVES: &[u8] =
+        include_bytes!("../tests/fixtures/dag_pb_leaves.car");
+    const DAG_PB_LEAVES_FILE: &[u8] =
+        include_bytes!("../tests/fixtures/dag_pb_leaves.bin");
+
+    fn unpack(car: &[u8], max_size: u64) -> Result<Vec<u8>> {
+        let mut output = vec![];
+        unpack_car(&mut Cursor::new(car), &mut output, max_size)?;
+        Ok(output)
+    }
+
+    fn varint(mut n: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        while n >= 0x80 {
+            bytes.push((n as u8 & 0x7f) | 0x80);
+            n >>= 7;
+        }
+        bytes.push(n as u8);
+        bytes
+    }
+
+    fn length_delimited(key: u8, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![key];
+        bytes.extend(varint(data.len() as u64));
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    /// CIDv1 of the block holding `data`
+    fn get_cid(codec: u8, data: &[u8]) -> Vec<u8> {
+        let mut cid = vec![1, codec, 0x12, 0x20];
+        cid.extend_from_slice(Sha256::digest(data).as_slice());
+        cid
+    }
+
+    /// Dag-pb file node holding `data` and linking to `links`
+    fn get_node(links: &[Vec<u8>], data: &[u8]) -> Vec<u8> {
+        let mut node = vec![];
+        for link in links {
+            node.extend(length_delimited(0x12, &length_delimited(0x0a, link)));
+        }
+        let mut unixfs = vec![0x08, 0x02];
+        unixfs.extend(length_delimited(0x12, data));
+        node.extend(length_delimited(0x0a, &unixfs));
+        node
+    }
+
+    fn get_car(root: &[u8], blocks: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut header = vec![0xa2, 0x65];
+        header.extend_from_slice(b"roots");
+        header.extend(vec![0x81, 0xd8, 0x2a, 0x58, root.len() as u8 + 1, 0]);
+        header.extend_from_slice(root);
+        header.push(0x67);
+        header.extend_from_slice(b"version");
+        header.push(0x01);
+
+        let mut car = varint(header.len() as u64);
+        car.extend(header);
+        for (cid, data) in blocks {
+            car.extend(varint((cid.len() + data.len()) as u64));
+            car.extend_from_slice(cid);
+            car.extend_from_slice(data);
+        }
+        car
+    }
+
+    #[test]
+    fn unpacks_raw_leaves() {
+        assert_eq!(unpack(RAW_LEAVES, 1024).unwrap(), RAW_LEAVES_FILE);
+    }
+
+    #[test]
+    fn unpacks_dag_pb_leaves() {
+        assert_eq!(unpack(DAG_PB_LEAVES, 1024).unwrap(), DAG_PB_LEAVES_FILE);
+    }
+
+    #[test]
+    fn archives_of_small_drives_fit() {
+        assert!(1 << get_archive_log2_size(10) >= RAW_LEAVES.len());
+        for log2_size in 3..62 {
+            let archive_log2_size = get_archive_log2_size(log2_size);
+            assert!(archive_log2_size > log2_size);
+            assert!(
+                1u64 << archive_log2_size
+                    >= (1u64 << log2_size) + ARCHIVE_OVERHEAD
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_content_over_the_drive_size() {
+        let size = RAW_LEAVES_FILE.len() as u64;
+        assert!(unpack(RAW_LEAVES, size).is_ok());
+        assert!(unpack(RAW_LEAVES, size - 1).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_archives() {
+        for len in &[0, 1, 20, RAW_LEAVES.len() - 1] {
+            assert!(unpack(&RAW_LEAVES[..*len], 1024).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_missing_blocks() {
+        let node = get_node(&[get_cid(0x55, b"data")], b"");
+        let root = get_cid(0x70, &node);
+        assert!(unpack(&get_car(&root, &[(root.clone(), node)]), 1024)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_blocks_not_matching_their_cid() {
+        let leaf = get_cid(0x55, b"data");
+        let node = get_node(slice::from_ref(&leaf), b"");
+        let root = get_cid(0x70, &node);
+        let blocks = vec![(root.clone(), node), (leaf, b"atad".to_vec())];
+        assert!(unpack(&get_car(&root, &blocks), 1024).is_err());
+
+        // a cycle needs a block linking to the CID of its own data
+        let root = get_cid(0x70, b"");
+        let node = get_node(slice::from_ref(&root), b"a");
+        assert!(
+            unpack(&get_car(&root, &[(root.clone(), node)]), 1 << 20).is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_hashes() {
+        let mut root = get_cid(0x55, b"data");
+        root[2] = 0x13;
+        let blocks = vec![(root.clone(), b"data".to_vec())];
+        assert!(unpack(&get_car(&root, &blocks), 1024).is_err());
+    }
+
+    #[test]
+    fn follows_repeated_links_within_the_size() {
+        let leaf = get_cid(0x55, b"data");
+        let node = get_node(&vec![leaf.clone(); 4], b"");
+        let root = get_cid(0x70, &node);
+        let blocks = vec![(root.clone(), node), (leaf, b"data".to_vec())];
+        let car = get_car(&root, &blocks);
+        assert_eq!(unpack(&car, 16).unwrap(), b"datadatadatadata".to_vec());
+        assert!(unpack(&car, 15).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_blocks() {
+        let leaf = get_cid(0x55, b"");
+        let node = get_node(&vec![leaf.clone(); 4], b"");
+        let root = get_cid(0x70, &node);
+        let blocks = vec![(root.clone(), node), (leaf, vec![])];
+        assert!(unpack(&get_car(&root, &blocks), 1024).is_err());
+    }
+
+    #[test]
+    fn rejects_deep_files() {
+        let leaf = get_node(&[], b"x");
+        let mut blocks = vec![(get_cid(0x70, &leaf), leaf)];
+        for _ in 0..MAX_DAG_DEPTH + 2 {
+            let node = get_node(&[blocks.last().unwrap().0.clone()], b"");
+            blocks.push((get_cid(0x70, &node), node));
+        }
+        let root = blocks.last().unwrap().0.clone();
+        assert!(unpack(&get_car(&root, &blocks), 1024).is_err());
+        // the same chain within the depth unpacks
+        let root = blocks[MAX_DAG_DEPTH].0.clone();
+        assert_eq!(unpack(&get_car(&root, &blocks), 1024).unwrap(), b"x");
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        // header length over the archive
+        assert!(unpack(&[0x40, 0xa2], 1024).is_err());
+        // no roots
+        assert!(unpack(&[0x01, 0xa0], 1024).is_err());
+        // nested beyond what a header needs
+        let mut nested = varint(40);
+        nested.extend(vec![0x81; 40]);
+        assert!(unpack(&nested, 1024).is_err());
+    }
+}
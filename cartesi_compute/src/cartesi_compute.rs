@@ -32,24 +32,24 @@ use super::ethereum_types::{Address, H256, U256};
 use super::hex;
 use super::transaction;
 use super::transaction::TransactionRequest;
+use super::abi::{self, AbiField};
+use super::compute_error::{ComputeError, ComputeResult};
+use super::emulator::{ArchiveEmulator, EmulatorBackend, Proof, ServiceClient};
+use super::proof::ContractProof;
+use super::keccak_hash::keccak;
+use super::config::{ComputeConfig, Mode};
+use super::node::{self, Node};
 use super::{
-    build_ipfs_get_key, build_logger_download_key, build_logger_submit_key,
-    build_machine_id, get_logger_response, Role,
+    car, gas, merkle, outbox, policy, results, schedule, session, templates,
 };
-use compute::{
-    build_session_end_key, build_session_proof_key, build_session_read_key,
-    build_session_run_key, build_session_write_key, build_session_replace_key,
-    cartesi_machine,
-    get_run_result, EndSessionRequest, NewSessionRequest, NewSessionResponse,
-    SessionGetProofRequest, SessionGetProofResponse, SessionReadMemoryRequest,
-    SessionReadMemoryResponse, SessionRunRequest, SessionRunResult,
-    SessionWriteMemoryRequest, SessionReplaceMemoryRangeRequest, EMULATOR_METHOD_END, EMULATOR_METHOD_NEW,
-    EMULATOR_METHOD_PROOF, EMULATOR_METHOD_READ, EMULATOR_METHOD_WRITE,
-    EMULATOR_METHOD_REPLACE,
-    EMULATOR_SERVICE_NAME,
+use super::{
+    build_drive_inbox_path, build_ipfs_add_key, build_ipfs_get_key, build_logger_download_key,
+    build_logger_provide_key, build_logger_submit_key, build_machine_id,
+    get_logger_response, Role,
 };
 use ipfs_service::{
-    GetFileRequest, GetFileResponse, GetFileResponseOneOf, IPFS_METHOD_GET,
+    AddFileRequest, AddFileResponse, AddFileResponseOneOf, GetFileRequest,
+    GetFileResponse, GetFileResponseOneOf, IPFS_METHOD_ADD, IPFS_METHOD_GET,
     IPFS_SERVICE_NAME,
 };
 use logger_service::{
@@ -57,92 +57,230 @@ use logger_service::{
     SubmitFileResponse, LOGGER_METHOD_DOWNLOAD, LOGGER_METHOD_SUBMIT,
 };
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
 
 pub struct CartesiCompute();
 
-#[derive(Serialize, Deserialize)]
-pub enum TupleType {
-    #[serde(rename = "(uint64,uint8,bytes,bytes,bytes32,address,bool,bool,bool)[]")]
-    DriveArrayTuple,
-    #[serde(rename = "(bool,bool,bool,uint64)")]
-    PartyTypeTuple,
+/// Mirror of `CartesiComputeInterface.State`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    WaitingProviders,
+    ProviderMissedDeadline,
+    WaitingChallengeDrives,
+    WaitingReveals,
+    WaitingClaim,
+    ClaimerMissedDeadline,
+    WaitingConfirmationDeadline,
+    WaitingChallengeResult,
+    ChallengerWon,
+    ClaimerWon,
+    ConsensusResult,
+}
+
+impl State {
+    /// Parse the zero padded state name returned by `getCurrentState`
+    pub fn from_bytes32(state: H256) -> ComputeResult<State> {
+        let name: Vec<u8> = state
+            .to_fixed_bytes()
+            .iter()
+            .take_while(|&n| *n != 0)
+            .map(|&n| n)
+            .collect();
+        String::from_utf8_lossy(&name).parse()
+    }
+
+    /// Whether the instance is over and nothing else will happen to it
+    pub fn is_finished(&self) -> bool {
+        match self {
+            State::ProviderMissedDeadline
+            | State::ClaimerMissedDeadline
+            | State::ChallengerWon
+            | State::ClaimerWon
+            | State::ConsensusResult => true,
+            State::WaitingProviders
+            | State::WaitingChallengeDrives
+            | State::WaitingReveals
+            | State::WaitingClaim
+            | State::WaitingConfirmationDeadline
+            | State::WaitingChallengeResult => false,
+        }
+    }
+
+    /// Whether a claim of the output was submitted
+    pub fn has_claim(&self) -> bool {
+        match self {
+            State::WaitingConfirmationDeadline
+            | State::WaitingChallengeResult
+            | State::ChallengerWon
+            | State::ClaimerWon
+            | State::ConsensusResult => true,
+            State::WaitingProviders
+            | State::ProviderMissedDeadline
+            | State::WaitingChallengeDrives
+            | State::WaitingReveals
+            | State::WaitingClaim
+            | State::ClaimerMissedDeadline => false,
+        }
+    }
+}
+
+impl FromStr for State {
+    type Err = ComputeError;
+
+    fn from_str(state: &str) -> ComputeResult<State> {
+        match state {
+            "WaitingProviders" => Ok(State::WaitingProviders),
+            "ProviderMissedDeadline" => Ok(State::ProviderMissedDeadline),
+            "WaitingChallengeDrives" => Ok(State::WaitingChallengeDrives),
+            "WaitingReveals" => Ok(State::WaitingReveals),
+            "WaitingClaim" => Ok(State::WaitingClaim),
+            "ClaimerMissedDeadline" => Ok(State::ClaimerMissedDeadline),
+            "WaitingConfirmationDeadline" => {
+                Ok(State::WaitingConfirmationDeadline)
+            }
+            "WaitingChallengeResult" => Ok(State::WaitingChallengeResult),
+            "ChallengerWon" => Ok(State::ChallengerWon),
+            "ClaimerWon" => Ok(State::ClaimerWon),
+            "ConsensusResult" => Ok(State::ConsensusResult),
+            _ => Err(ComputeError::InvalidState(format!(
+                "unknown cartesi compute state {}",
+                state
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct DriveParsed(
-    U256,    // position
-    U256,    // log2Size
-    String,  // directValue
-    String,  // loggerIpfsPath
-    H256,    // loggerRootHash
-    Address, // provider
-    bool,    // needsProvider
-    bool,    // needsLogger
-    bool,    // downloadAsCAR
-);
-
-#[derive(Serialize, Deserialize)]
-pub struct DriveArray {
-    pub name: String,
-    #[serde(rename = "type")]
-    pub ty: TupleType,
-    pub value: Vec<DriveParsed>,
+abi_tuple! {
+    /// Mirror of `CartesiComputeInterface.Drive`
+    pub struct DriveParsed {
+        position: U256 = ("position", "uint64"),
+        log2_size: U256 = ("driveLog2Size", "uint8"),
+        direct_value: String = ("directValue", "bytes"),
+        ipfs_path: String = ("loggerIpfsPath", "bytes"),
+        root_hash: H256 = ("loggerRootHash", "bytes32"),
+        provider: Address = ("provider", "address"),
+        waits_provider: bool = ("waitsProvider", "bool"),
+        needs_logger: bool = ("needsLogger", "bool"),
+        download_as_car: bool = ("downloadAsCAR", "bool"),
+    }
 }
 
 #[derive(Serialize, Debug)]
 pub struct Drive {
-    position: U256,
-    log2_size: U256,
-    direct_value: Vec<u8>,
-    ipfs_path: String,
-    root_hash: H256,
-    provider: Address,
-    waits_provider: bool,
-    needs_logger: bool,
-    download_as_car: bool,
+    pub position: U256,
+    pub log2_size: U256,
+    pub direct_value: Vec<u8>,
+    pub ipfs_path: String,
+    pub root_hash: H256,
+    pub provider: Address,
+    pub waits_provider: bool,
+    pub needs_logger: bool,
+    pub download_as_car: bool,
 }
 
-impl From<&DriveParsed> for Drive {
-    fn from(parsed: &DriveParsed) -> Drive {
-        Drive {
-            position: parsed.0,
-            log2_size: parsed.1,
-            direct_value: hex::decode(&parsed.2[2..]).unwrap(),
-            ipfs_path: hex::decode(&parsed.3[2..])
-                .and_then(|vec_u8| {
-                    let removed_trailing_zeros = vec_u8
-                        .iter()
-                        .take_while(|&n| *n != 0)
-                        .map(|&n| n)
-                        .collect();
-                    Ok(String::from_utf8(removed_trailing_zeros).unwrap())
-                })
-                .unwrap(),
-            root_hash: parsed.4,
-            provider: parsed.5,
-            waits_provider: parsed.6,
-            needs_logger: parsed.7,
-            download_as_car: parsed.8
-        }
+impl TryFrom<&DriveParsed> for Drive {
+    type Error = ComputeError;
+
+    fn try_from(parsed: &DriveParsed) -> ComputeResult<Drive> {
+        let ipfs_path: Vec<u8> =
+            decode_drive_bytes(parsed, &parsed.ipfs_path)?
+                .into_iter()
+                .take_while(|&n| n != 0)
+                .collect();
+        let ipfs_path = String::from_utf8(ipfs_path).map_err(|e| {
+            ComputeError::InvalidDrive {
+                position: parsed.position,
+                reason: format!("has invalid ipfs path: {}", e),
+            }
+        })?;
+
+        Ok(Drive {
+            position: parsed.position,
+            log2_size: parsed.log2_size,
+            direct_value: decode_drive_bytes(parsed, &parsed.direct_value)?,
+            ipfs_path: ipfs_path,
+            root_hash: parsed.root_hash,
+            provider: parsed.provider,
+            waits_provider: parsed.waits_provider,
+            needs_logger: parsed.needs_logger,
+            download_as_car: parsed.download_as_car,
+        })
+    }
+}
+
+/// Decode a `0x` prefixed bytes field of a drive
+fn decode_drive_bytes(
+    parsed: &DriveParsed,
+    value: &str,
+) -> ComputeResult<Vec<u8>> {
+    let invalid = || ComputeError::InvalidDrive {
+        position: parsed.position,
+        reason: format!("has invalid bytes field: {}", value),
+    };
+    if !value.starts_with("0x") {
+        return Err(invalid());
     }
+    hex::decode(&value[2..]).map_err(|_| invalid())
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct PartyType {
-    pub name: String,
-    #[serde(rename = "type")]
-    pub ty: TupleType,
-    pub value: PartyParsed,
+impl Drive {
+    /// Check the drive fits an aligned memory range of its log2 size
+    pub fn check_range(&self) -> ComputeResult<()> {
+        let invalid = |reason: String| ComputeError::InvalidDrive {
+            position: self.position,
+            reason: reason,
+        };
+        if self.log2_size < U256::from(3) || self.log2_size >= U256::from(64) {
+            return Err(invalid(format!(
+                "has invalid log2 size {}",
+                self.log2_size
+            )));
+        }
+        let log2_size = self.log2_size.as_u64();
+
+        let size = 1u64 << log2_size;
+        if self.position > U256::from(u64::max_value())
+            || self.position.as_u64() % size != 0
+        {
+            return Err(invalid(format!(
+                "is not aligned to its log2 size {}",
+                log2_size
+            )));
+        }
+
+        if !self.needs_logger && self.direct_value.len() as u64 > size {
+            return Err(invalid(format!(
+                "has {} bytes, exceeding log2 size {}",
+                self.direct_value.len(),
+                log2_size
+            )));
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct PartyParsed(
-    bool, // isParty
-    bool, // hasVoted
-    bool, // hasCheated
-    U256, // partyArrayIndex
-);
+abi_tuple! {
+    /// Mirror of `CartesiComputeInterface.Party`
+    pub struct PartyParsed {
+        is_party: bool = ("isParty", "bool"),
+        has_voted: bool = ("hasVoted", "bool"),
+        has_cheated: bool = ("hasCheated", "bool"),
+        array_idx: U256 = ("arrayIdx", "uint64"),
+    }
+}
 
 #[derive(Serialize, Debug)]
 pub struct Party {
@@ -155,28 +293,43 @@ pub struct Party {
 impl From<PartyParsed> for Party {
     fn from(parsed: PartyParsed) -> Party {
         Party {
-            isParty: parsed.0,
-            hasVoted: parsed.1,
-            hasCheated: parsed.2,
-            arrayIdx: parsed.3,
+            isParty: parsed.is_party,
+            hasVoted: parsed.has_voted,
+            hasCheated: parsed.has_cheated,
+            arrayIdx: parsed.array_idx,
         }
     }
 }
 
-// !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-// these two structs and the From trait below shuld be
-// obtained from a simple derive
-// !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
-#[derive(Serialize, Deserialize)]
-pub struct CartesiComputeCtxParsed(
-    U256Array,    // finalTime, deadline, outputPosition, outputLog2Size
-    AddressArray, // challenger, claimer
-    Bytes32Array, // templateHash, initialHash, claimedFinalHash, currentState
-    BytesField,   // claimedOutput
-    DriveArray,
-    PartyType,
-    BoolField
-);
+abi_outputs! {
+    /// Outputs of `CartesiCompute.getState`, the deployed contracts
+    /// leave the first five unnamed
+    pub struct CartesiComputeCtxParsed {
+        // finalTime, deadline, outputPosition, outputLog2Size
+        uint_values: Vec<U256> = ""; U256Array,
+        // challenger, claimer
+        address_values: Vec<Address> = ""; AddressArray,
+        // templateHash, initialHash, claimedFinalHash, currentState
+        bytes32_values: Vec<H256> = ""; Bytes32Array,
+        claimed_output: Vec<u8> = ""; BytesField,
+        // only the pending drive while waiting for providers or reveals
+        drives: Vec<DriveParsed> = ""; AbiField<Vec<DriveParsed>>,
+        user: PartyParsed = "user"; AbiField<PartyParsed>,
+        no_challenge_drive: bool = "noChallengeDrive"; BoolField,
+    }
+}
+
+impl CartesiComputeCtxParsed {
+    /// Check the deployed contract returns the outputs this struct
+    /// expects, so that a change to `getState` fails at startup
+    pub fn check_abi(abi_path: &str) -> Result<()> {
+        abi::check_function_outputs(
+            abi_path,
+            "getState",
+            CartesiComputeCtxParsed::outputs(),
+        )
+    }
+}
 
 #[derive(Serialize, Debug)]
 pub struct CartesiComputeCtx {
@@ -190,51 +343,120 @@ pub struct CartesiComputeCtx {
     pub output_position: U256,
     pub output_log2_size: U256,
     pub final_time: U256,
-    pub current_state: String,
+    pub current_state: State,
     pub input_drives: Vec<Drive>,
     pub partyState: Party,
     pub noChallengeDrive: bool,
 }
 
-impl From<CartesiComputeCtxParsed> for CartesiComputeCtx {
-    fn from(parsed: CartesiComputeCtxParsed) -> CartesiComputeCtx {
-        CartesiComputeCtx {
-            final_time: parsed.0.value[0],
-            deadline: parsed.0.value[1],
-            output_position: parsed.0.value[2],
-            output_log2_size: parsed.0.value[3],
-            challenger: parsed.1.value[0],
-            claimer: parsed.1.value[1],
-            template_hash: parsed.2.value[0],
-            initial_hash: parsed.2.value[1],
-            claimed_final_hash: parsed.2.value[2],
-            current_state: String::from_utf8(
-                parsed.2.value[3]
-                    .to_fixed_bytes()
-                    .to_vec()
-                    .iter()
-                    .take_while(|&n| *n != 0)
-                    .map(|&n| n)
-                    .collect(),
-            )
-            .unwrap(),
-            claimed_output: parsed.3.value,
-            input_drives: parsed.4.value.iter().map(|d| d.into()).collect(),
-            partyState: parsed.5.value.into(),
-            noChallengeDrive: parsed.6.value.into()
+impl TryFrom<CartesiComputeCtxParsed> for CartesiComputeCtx {
+    type Error = ComputeError;
+
+    fn try_from(
+        parsed: CartesiComputeCtxParsed,
+    ) -> ComputeResult<CartesiComputeCtx> {
+        check_length("uintValues", &parsed.uint_values, 4)?;
+        check_length("addressValues", &parsed.address_values, 2)?;
+        check_length("bytes32Values", &parsed.bytes32_values, 4)?;
+
+        let input_drives = parsed
+            .drives
+            .iter()
+            .map(Drive::try_from)
+            .collect::<ComputeResult<Vec<Drive>>>()?;
+
+        Ok(CartesiComputeCtx {
+            final_time: parsed.uint_values[0],
+            deadline: parsed.uint_values[1],
+            output_position: parsed.uint_values[2],
+            output_log2_size: parsed.uint_values[3],
+            challenger: parsed.address_values[0],
+            claimer: parsed.address_values[1],
+            template_hash: parsed.bytes32_values[0],
+            initial_hash: parsed.bytes32_values[1],
+            claimed_final_hash: parsed.bytes32_values[2],
+            current_state: State::from_bytes32(parsed.bytes32_values[3])?,
+            claimed_output: parsed.claimed_output,
+            input_drives: input_drives,
+            partyState: parsed.user.into(),
+            noChallengeDrive: parsed.no_challenge_drive,
+        })
+    }
+}
+
+/// What `get_pretty_instance` shows: the contract state together with
+/// what this node found out about the instance
+#[derive(Serialize)]
+struct PrettyCartesiComputeCtx<'a> {
+    #[serde(flatten)]
+    ctx: &'a CartesiComputeCtx,
+    verification: Option<results::Verification>,
+    verdict: Option<results::Verdict>,
+    template: templates::TemplateStatus,
+    /// Limits of the admission policy the instance does not respect,
+    /// the node does not work on it unless empty
+    rejections: Vec<policy::Violation>,
+    /// Transactions waiting for approval or recorded by a dry run
+    outbox: Vec<outbox::HeldTransaction>,
+    /// Seconds left before the deadline of the current state
+    time_left: Option<u64>,
+}
+
+fn check_length<T>(
+    name: &str,
+    values: &Vec<T>,
+    expected: usize,
+) -> ComputeResult<()> {
+    if values.len() != expected {
+        return Err(ComputeError::InvalidState(format!(
+            "getState returned {} {}, expected {}",
+            values.len(),
+            name,
+            expected
+        )));
+    }
+    Ok(())
+}
+
+impl CartesiComputeCtx {
+    /// The drive waiting for its provider, if any
+    pub fn pending_provider_drive(&self) -> Option<&Drive> {
+        self.pending_drive(State::WaitingProviders)
+    }
+
+    /// The drive waiting to be revealed, if any
+    pub fn pending_reveal_drive(&self) -> Option<&Drive> {
+        self.pending_drive(State::WaitingReveals)
+    }
+
+    /// getState returns only the pending drive while waiting for it
+    fn pending_drive(&self, state: State) -> Option<&Drive> {
+        if self.current_state != state || self.input_drives.len() != 1 {
+            return None;
         }
+        self.input_drives.first()
     }
 }
 
-impl DApp<()> for CartesiCompute {
-    /// React to the cartesi compute contract, submitting drives,
-    /// submitting result, confirming or challenging result
+/// Whether the deployed contract takes the ipfs path of revealed drives,
+/// the ones deployed before `revealLoggerDriveWithIpfsPath` don't
+fn reveals_ipfs_path(config: &ComputeConfig) -> bool {
+    match &config.main_concern {
+        Some(concern) => {
+            abi::has_function(&concern.abi, "revealLoggerDriveWithIpfsPath")
+        }
+        None => false,
+    }
+}
+
+impl CartesiCompute {
+    /// Decide how to react to the cartesi compute contract, submitting
+    /// drives, submitting result, confirming or challenging result
     /// when appropriate
-    fn react(
+    fn get_reaction(
+        node: &Node,
         instance: &state::Instance,
         archive: &Archive,
-        _post_payload: &Option<String>,
-        _: &(),
     ) -> Result<Reaction> {
         // get context (state) of the CartesiCompute instance
         let parsed: CartesiComputeCtxParsed =
@@ -244,73 +466,148 @@ impl DApp<()> for CartesiCompute {
                     &instance.json_data
                 )
             })?;
-        let ctx: CartesiComputeCtx = parsed.into();
+        let ctx = CartesiComputeCtx::try_from(parsed)?;
         trace!("Context for cartesi compute (index {}) {:?}", instance.index, ctx);
 
-        let machine_id =
-            build_machine_id(instance.index, &instance.concern.user_address);
+        CartesiCompute::react_to_instance(
+            node,
+            archive,
+            &ArchiveEmulator::new(archive, node),
+            &|drive: &Drive, ipfs_only: bool| {
+                fetch_drive_image(node, archive, drive, ipfs_only)
+            },
+            &instance.concern,
+            instance.index,
+            ctx,
+            instance.sub_instances.get(0).map(|vg_instance| &**vg_instance),
+        )
+    }
+
+    /// Decide how to react to the instance at `index` in state `ctx`, as
+    /// `concern.user_address`. Machines run on `emulator` and other
+    /// requests go through `archive`, except for the images of drives
+    /// kept off-chain, which `fetch_drive` gives:
+    /// from Ipfs only when its flag is set, failing with
+    /// `ResponseInvalidError` if the drive isn't there, or from any source
+    /// otherwise. `vg_instance` is the verification game of the instance,
+    /// if one was started.
+    pub fn react_to_instance(
+        node: &Node,
+        archive: &dyn ServiceClient,
+        emulator: &dyn EmulatorBackend,
+        fetch_drive: &dyn Fn(&Drive, bool) -> Result<String>,
+        concern: &Concern,
+        index: U256,
+        ctx: CartesiComputeCtx,
+        vg_instance: Option<&state::Instance>,
+    ) -> Result<Reaction> {
+        let machine_id = build_machine_id(index, &concern.user_address);
+        let no_challenge_drive = ctx.noChallengeDrive;
+        let get_drive_image =
+            |drive: &Drive| fetch_drive(drive, no_challenge_drive);
 
         // these states should not occur as they indicate an innactive instance,
         // but it is possible that the blockchain state changed between queries
-        match ctx.current_state.as_ref() {
-            "ProviderMissedDeadline"
-            | "ClaimerMissedDeadline"
-            | "ChallengerWon"
-            | "ClaimerWon"
-            | "ConsensusResult" => {
-                let request = EndSessionRequest {
-                    session_id: machine_id.clone(),
-                    silent: true,
-                };
-
-                // send terminateSession request to the emulator service
-                let _processed_response = archive.get_response(
-                    EMULATOR_SERVICE_NAME.to_string(),
-                    build_session_end_key(machine_id.clone()),
-                    EMULATOR_METHOD_END.to_string(),
-                    request.into(),
-                )?;
+        if ctx.current_state.is_finished() {
+            node.sessions.end(emulator, &machine_id)?;
+            node.scheduler.release(&machine_id);
+            return Ok(Reaction::Idle);
+        }
 
-                return Ok(Reaction::Idle);
+        // leave instances beyond the operator limits alone, before any
+        // emulator or ipfs work is done for them
+        let violations = policy::get_rejections(
+            &node.config.policy,
+            node.config.mode,
+            &ctx,
+            concern.user_address,
+        );
+        if !violations.is_empty() {
+            for violation in &violations {
+                warn!(
+                    "Cartesi compute (index {}) rejected: {}",
+                    index, violation
+                );
             }
-            _ => {}
-        };
+            return Ok(Reaction::Idle);
+        }
+
+        // a watching node only verifies claims, whatever its role
+        if node.config.mode == Mode::Watch {
+            return watch(
+                node,
+                emulator,
+                &get_drive_image,
+                concern,
+                index,
+                ctx,
+                machine_id,
+            );
+        }
 
         // if we reach this code, the instance is active, get user's role
-        let role = match instance.concern.user_address {
-            cl if (cl == ctx.claimer) => Role::Claimer,
-            ch if (ch == ctx.challenger) => Role::Challenger,
-            _ => Role::Other,
+        let role = get_role(concern.user_address, &ctx);
+        trace!("Role played (index {}) is: {:?}", index, role);
+
+        // warn as soon as the work due in this state may miss the deadline
+        let due_work = match (&role, ctx.current_state) {
+            (Role::Claimer, State::WaitingClaim) => Some(true),
+            (Role::Voter, State::WaitingConfirmationDeadline) => Some(true),
+            // the claimer only claims once this deadline is over
+            (Role::Voter, State::WaitingChallengeDrives) => Some(false),
+            _ => None,
         };
-        trace!("Role played (index {}) is: {:?}", instance.index, role);
-
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .chain_err(|| "System time before UNIX_EPOCH")?
-            .as_secs();
+        if let Some(runs_machine) = due_work {
+            let work = schedule::estimate_work(
+                &node.config,
+                &ctx,
+                concern.user_address,
+                runs_machine,
+            );
+            schedule::check_time_left(
+                &node.config.schedule,
+                &*node.clock,
+                index,
+                &ctx,
+                work,
+            )?;
+        }
 
-        match ctx.current_state.as_ref() {
-            "WaitingProviders" => {
-                if instance.concern.user_address != ctx.input_drives[0].provider
-                {
+        match ctx.current_state {
+            State::WaitingProviders => {
+                if role.is_party() {
+                    prefetch_drives(fetch_drive, &ctx, concern.user_address);
+                }
+                let drive = ctx.pending_provider_drive().ok_or(Error::from(
+                    ErrorKind::InvalidContractState(
+                        "No pending provider drive".into(),
+                    ),
+                ))?;
+                if concern.user_address != drive.provider {
+                    // only parties abort the instance
+                    if !role.is_party() {
+                        return Ok(Reaction::Idle);
+                    }
                     // wait others to provide drives
                     // or abort if the deadline is over
                     return abort_by_deadline_or_idle(
-                        &instance.concern,
-                        instance.index,
+                        node,
+                        concern,
+                        index,
                         ctx.deadline.as_u64(),
                     );
                 }
+                // we are the provider of the pending drive
+                return provide_drive(node, archive, concern, index, drive);
             }
-            "WaitingChallengeDrives" => {
+            State::WaitingChallengeDrives => {
+                // only parties may challenge the drives
+                if !role.is_party() {
+                    return Ok(Reaction::Idle);
+                }
                 for drive in &ctx.input_drives {
                     if drive.needs_logger {
-                        if let Err(e) = get_ipfs_drive(
-                            archive,
-                            drive.ipfs_path.clone(),
-                            drive.log2_size.as_u64() as u32,
-                            drive.root_hash,
-                        ) {
+                        if let Err(e) = fetch_drive(drive, true) {
                             if ctx.noChallengeDrive {
                                 return Err(e);
                             }
@@ -323,12 +620,10 @@ impl DApp<()> for CartesiCompute {
                                     if drive.provider != Address::zero() {
                                         let request = TransactionRequest {
                                             contract_name: None, /* Name not needed, is concern */
-                                            concern: instance.concern.clone(),
+                                            concern: concern.clone(),
                                             value: U256::from(0),
                                             function: "challengeDrives".into(),
-                                            data: vec![Token::Uint(
-                                                instance.index,
-                                            )],
+                                            data: vec![Token::Uint(index)],
                                             gas: None,
                                             strategy:
                                                 transaction::Strategy::Simplest,
@@ -346,22 +641,35 @@ impl DApp<()> for CartesiCompute {
                     }
                 }
             }
-            "WaitingReveals" => {
-                if instance.concern.user_address != ctx.input_drives[0].provider
-                {
+            State::WaitingReveals => {
+                if role.is_party() {
+                    prefetch_drives(fetch_drive, &ctx, concern.user_address);
+                }
+                let drive = ctx.pending_reveal_drive().ok_or(Error::from(
+                    ErrorKind::InvalidContractState(
+                        "No pending reveal drive".into(),
+                    ),
+                ))?;
+                if concern.user_address != drive.provider {
+                    // only parties abort the instance
+                    if !role.is_party() {
+                        return Ok(Reaction::Idle);
+                    }
                     // wait others to reveal drives
                     // or abort if the deadline is over
                     return abort_by_deadline_or_idle(
-                        &instance.concern,
-                        instance.index,
+                        node,
+                        concern,
+                        index,
                         ctx.deadline.as_u64(),
                     );
                 }
-                let root = ctx.input_drives[0].root_hash.clone();
+                let root = drive.root_hash.clone();
+                let storage = &node.config.storage;
                 let request = SubmitFileRequest {
-                    path: format!("{:x}", root),
+                    path: storage.drive_name(&format!("{:x}", root)),
                     page_log2_size: 3,
-                    tree_log2_size: ctx.input_drives[0].log2_size.as_u64(),
+                    tree_log2_size: drive.log2_size.as_u64(),
                 };
 
                 let processed_response: SubmitFileResponse =
@@ -386,67 +694,142 @@ impl DApp<()> for CartesiCompute {
                     processed_response.root
                 );
 
-                let request = TransactionRequest {
-                    contract_name: None, // Name not needed, is concern
-                    concern: instance.concern.clone(),
-                    value: U256::from(0),
-                    function: "revealLoggerDrive".into(),
-                    data: vec![Token::Uint(instance.index)],
-                    gas: None,
-                    strategy: transaction::Strategy::Simplest,
+                // publish the drive to ipfs as well, so others can skip
+                // downloading it from the logger, when the deployed
+                // contract can record where
+                let ipfs_path = if drive.ipfs_path == ""
+                    && reveals_ipfs_path(&node.config)
+                {
+                    match add_ipfs_drive(
+                        archive,
+                        storage.drive_path(&format!("{:x}", root)),
+                    ) {
+                        Ok(ipfs_path) => Some(ipfs_path),
+                        Err(e) => match e.kind() {
+                            ErrorKind::ServiceNeedsRetry(..) => {
+                                return Err(e);
+                            }
+                            _ => {
+                                warn!(
+                                    "Fail to add drive {:x} to Ipfs: {}",
+                                    root, e
+                                );
+                                None
+                            }
+                        },
+                    }
+                } else {
+                    None
+                };
+
+                let request = match ipfs_path {
+                    Some(ipfs_path) => TransactionRequest {
+                        contract_name: None, // Name not needed, is concern
+                        concern: concern.clone(),
+                        value: U256::from(0),
+                        function: "revealLoggerDriveWithIpfsPath".into(),
+                        data: vec![
+                            Token::Uint(index),
+                            Token::Bytes(ipfs_path.into_bytes()),
+                        ],
+                        gas: None,
+                        strategy: transaction::Strategy::Simplest,
+                    },
+                    None => TransactionRequest {
+                        contract_name: None, // Name not needed, is concern
+                        concern: concern.clone(),
+                        value: U256::from(0),
+                        function: "revealLoggerDrive".into(),
+                        data: vec![Token::Uint(index)],
+                        gas: None,
+                        strategy: transaction::Strategy::Simplest,
+                    },
                 };
                 return Ok(Reaction::Transaction(request));
             }
-            _ => {}
+            State::WaitingClaim
+            | State::WaitingConfirmationDeadline
+            | State::WaitingChallengeResult => {}
+            State::ProviderMissedDeadline
+            | State::ClaimerMissedDeadline
+            | State::ChallengerWon
+            | State::ClaimerWon
+            | State::ConsensusResult => {
+                unreachable!("finished states are handled above")
+            }
         };
 
         match role {
-            Role::Claimer => match ctx.current_state.as_ref() {
-                "WaitingClaim" => {
+            Role::Claimer => match ctx.current_state {
+                State::WaitingClaim => {
                     // calculate machine output
-                    return react_by_machine_output(
-                        archive,
-                        &instance.concern,
-                        instance.index,
-                        &role,
-                        ctx.input_drives,
-                        ctx.template_hash,
-                        ctx.claimed_final_hash,
-                        ctx.final_time,
-                        ctx.output_position,
-                        ctx.output_log2_size,
-                        machine_id,
-                        ctx.noChallengeDrive,
-                    );
-                }
-                "WaitingChallengeDrives" => {
-                    // no one challenges the drives, claim output directly
-                    if current_time > ctx.deadline.as_u64() {
-                        return react_by_machine_output(
-                            archive,
-                            &instance.concern,
-                            instance.index,
+                    return run_scheduled(node, &machine_id, ctx.deadline, || {
+                        react_by_machine_output(
+                            node,
+                            emulator,
+                            &get_drive_image,
+                            concern,
+                            index,
                             &role,
                             ctx.input_drives,
                             ctx.template_hash,
+                            ctx.initial_hash,
                             ctx.claimed_final_hash,
+                            ctx.claimed_output,
                             ctx.final_time,
                             ctx.output_position,
                             ctx.output_log2_size,
-                            machine_id,
-                            ctx.noChallengeDrive,
+                            machine_id.clone(),
+                        )
+                    });
+                }
+                State::WaitingChallengeDrives => {
+                    // no one challenges the drives, claim output directly
+                    if node.clock.now()? > ctx.deadline.as_u64() {
+                        return run_scheduled(
+                            node,
+                            &machine_id,
+                            ctx.deadline,
+                            || {
+                                react_by_machine_output(
+                                    node,
+                                    emulator,
+                                    &get_drive_image,
+                                    concern,
+                                    index,
+                                    &role,
+                                    ctx.input_drives,
+                                    ctx.template_hash,
+                                    ctx.initial_hash,
+                                    ctx.claimed_final_hash,
+                                    ctx.claimed_output,
+                                    ctx.final_time,
+                                    ctx.output_position,
+                                    ctx.output_log2_size,
+                                    machine_id.clone(),
+                                )
+                            },
                         );
                     }
+                    // have the output ready once the deadline is over
+                    run_early(
+                        node,
+                        emulator,
+                        &get_drive_image,
+                        index,
+                        &ctx,
+                        &machine_id,
+                    );
                     return Ok(Reaction::Idle);
                 }
-                "WaitingChallengeResult" => {
+                State::WaitingChallengeResult => {
                     // we inspect the verification contract
-                    let vg_instance = instance.sub_instances.get(0).ok_or(
-                        Error::from(ErrorKind::InvalidContractState(format!(
+                    let vg_instance = vg_instance.ok_or(Error::from(
+                        ErrorKind::InvalidContractState(format!(
                             "There is no vg instance {}",
                             ctx.current_state
-                        ))),
-                    )?;
+                        )),
+                    ))?;
                     let vg_parsed: VGCtxParsed =
                         serde_json::from_str(&vg_instance.json_data)
                             .chain_err(|| {
@@ -462,15 +845,15 @@ impl DApp<()> for CartesiCompute {
                             // claim victory in cartesi compute contract
                             info!(
                                 "Claiming victory for Cartesi Compute (index: {})",
-                                instance.index
+                                index
                             );
                             let request = TransactionRequest {
                                 contract_name: None, /* Name not needed, is
                                                       * concern */
-                                concern: instance.concern.clone(),
+                                concern: concern.clone(),
                                 value: U256::from(0),
                                 function: "winByVG".into(),
-                                data: vec![Token::Uint(instance.index)],
+                                data: vec![Token::Uint(index)],
                                 gas: None,
                                 strategy: transaction::Strategy::Simplest,
                             };
@@ -482,74 +865,134 @@ impl DApp<()> for CartesiCompute {
                         }
                         _ => {
                             // verification game is still active,
-                            // pass control to the appropriate dapp
-                            return VG::react(
-                                vg_instance,
-                                archive,
-                                &None,
+                            // pass control to the appropriate dapp,
+                            // which runs on our emulator session
+                            let archive =
+                                archive.as_archive().ok_or(Error::from(
+                                    "The verification game is only played \
+                                     through the dispatcher archive",
+                                ))?;
+                            return with_session(
+                                node,
+                                emulator,
+                                &get_drive_image,
                                 &machine_id,
+                                ctx.template_hash,
+                                &ctx.input_drives,
+                                &|session: &session::Session| {
+                                    VG::react(
+                                        vg_instance,
+                                        archive,
+                                        &None,
+                                        &session.session_id,
+                                    )
+                                },
                             );
                         }
                     }
                 }
-                "WaitingConfirmationDeadline" => {
+                State::WaitingConfirmationDeadline => {
                     // wait for the challenger to confirm/challenge
                     // or claim consensus if the deadline is over
                     return abort_by_deadline_or_idle(
-                        &instance.concern,
-                        instance.index,
+                        node,
+                        concern,
+                        index,
                         ctx.deadline.as_u64(),
                     );
                 }
-                _ => {
+                State::WaitingProviders
+                | State::WaitingReveals
+                | State::ProviderMissedDeadline
+                | State::ClaimerMissedDeadline
+                | State::ChallengerWon
+                | State::ClaimerWon
+                | State::ConsensusResult => {
                     return Ok(Reaction::Idle);
                 }
             },
-            Role::Other => match ctx.current_state.as_ref() {
-                "WaitingClaim" => {
+            Role::Voter => match ctx.current_state {
+                State::WaitingClaim => {
+                    // have the output ready to check the claim against
+                    run_early(
+                        node,
+                        emulator,
+                        &get_drive_image,
+                        index,
+                        &ctx,
+                        &machine_id,
+                    );
                     // wait for the claimer to claim output
                     // or abort if the deadline is over
                     return abort_by_deadline_or_idle(
-                        &instance.concern,
-                        instance.index,
+                        node,
+                        concern,
+                        index,
                         ctx.deadline.as_u64(),
                     );
                 }
-                "WaitingConfirmationDeadline" => {
-                    if ctx.partyState.hasVoted {
-                        return Ok(Reaction::Idle);
-                    }
+                State::WaitingConfirmationDeadline => {
                     // determine the reaction based on the calculated machine
                     // output
-                    return react_by_machine_output(
-                        archive,
-                        &instance.concern,
-                        instance.index,
-                        &role,
-                        ctx.input_drives,
-                        ctx.template_hash,
-                        ctx.claimed_final_hash,
-                        ctx.final_time,
-                        ctx.output_position,
-                        ctx.output_log2_size,
-                        machine_id,
-                        ctx.noChallengeDrive,
-                    );
+                    return run_scheduled(node, &machine_id, ctx.deadline, || {
+                        react_by_machine_output(
+                            node,
+                            emulator,
+                            &get_drive_image,
+                            concern,
+                            index,
+                            &role,
+                            ctx.input_drives,
+                            ctx.template_hash,
+                            ctx.initial_hash,
+                            ctx.claimed_final_hash,
+                            ctx.claimed_output,
+                            ctx.final_time,
+                            ctx.output_position,
+                            ctx.output_log2_size,
+                            machine_id.clone(),
+                        )
+                    });
+                }
+                State::WaitingProviders
+                | State::WaitingChallengeDrives
+                | State::WaitingReveals
+                | State::WaitingChallengeResult
+                | State::ProviderMissedDeadline
+                | State::ClaimerMissedDeadline
+                | State::ChallengerWon
+                | State::ClaimerWon
+                | State::ConsensusResult => {
+                    return Ok(Reaction::Idle);
                 }
-                _ => {
+            },
+            // ending the instance when the claimer misses its deadline is
+            // left to the parties that still have to vote
+            Role::Voted | Role::Observer => match ctx.current_state {
+                State::WaitingProviders
+                | State::WaitingChallengeDrives
+                | State::WaitingReveals
+                | State::WaitingClaim
+                | State::WaitingConfirmationDeadline
+                | State::WaitingChallengeResult
+                | State::ProviderMissedDeadline
+                | State::ClaimerMissedDeadline
+                | State::ChallengerWon
+                | State::ClaimerWon
+                | State::ConsensusResult => {
                     return Ok(Reaction::Idle);
                 }
             },
 
-            Role::Challenger => match ctx.current_state.as_ref() {
-                "WaitingChallengeResult" => {
+            Role::Challenger => match ctx.current_state {
+                State::WaitingChallengeResult => {
                     // we inspect the verification contract
-                    let vg_instance = instance.sub_instances.get(0).ok_or(
-                        Error::from(ErrorKind::InvalidContractState(format!(
+                    let vg_instance = vg_instance.ok_or(Error::from(
+                        ErrorKind::InvalidContractState(format!(
                             "There is no vg instance {}",
                             ctx.current_state
-                        ))),
-                    )?;
+                        )),
+                    ))?;
                     let vg_parsed: VGCtxParsed =
                         serde_json::from_str(&vg_instance.json_data)
                             .chain_err(|| {
@@ -565,15 +1008,15 @@ impl DApp<()> for CartesiCompute {
                             // claim victory in cartesi compute contract
                             info!(
                                 "Claiming victory for Cartesi Compute (index: {})",
-                                instance.index
+                                index
                             );
                             let request = TransactionRequest {
                                 contract_name: None, /* Name not needed, is
                                                       * concern */
-                                concern: instance.concern.clone(),
+                                concern: concern.clone(),
                                 value: U256::from(0),
                                 function: "winByVG".into(),
-                                data: vec![Token::Uint(instance.index)],
+                                data: vec![Token::Uint(index)],
                                 gas: None,
                                 strategy: transaction::Strategy::Simplest,
                             };
@@ -585,22 +1028,79 @@ impl DApp<()> for CartesiCompute {
                         }
                         _ => {
                             // verification game is still active,
-                            // pass control to the appropriate dapp
-                            return VG::react(
-                                vg_instance,
-                                archive,
-                                &None,
+                            // pass control to the appropriate dapp,
+                            // which runs on our emulator session
+                            let archive =
+                                archive.as_archive().ok_or(Error::from(
+                                    "The verification game is only played \
+                                     through the dispatcher archive",
+                                ))?;
+                            return with_session(
+                                node,
+                                emulator,
+                                &get_drive_image,
                                 &machine_id,
+                                ctx.template_hash,
+                                &ctx.input_drives,
+                                &|session: &session::Session| {
+                                    VG::react(
+                                        vg_instance,
+                                        archive,
+                                        &None,
+                                        &session.session_id,
+                                    )
+                                },
                             );
                         }
                     }
                 }
-                _ => {
+                // getState only names the challenger while its challenge
+                // is decided; once decided, a winning challenger becomes
+                // the claimer and a losing one has voted for good
+                State::WaitingProviders
+                | State::WaitingChallengeDrives
+                | State::WaitingReveals
+                | State::WaitingClaim
+                | State::WaitingConfirmationDeadline
+                | State::ProviderMissedDeadline
+                | State::ClaimerMissedDeadline
+                | State::ChallengerWon
+                | State::ClaimerWon
+                | State::ConsensusResult => {
                     return Ok(Reaction::Idle);
                 }
             },
         };
     }
+}
+
+impl DApp<()> for CartesiCompute {
+    /// React to the cartesi compute contract, holding back transactions
+    /// as the mode of the node requires. Payloads posted to the instance
+    /// through the query port approve held transactions.
+    fn react(
+        instance: &state::Instance,
+        archive: &Archive,
+        post_payload: &Option<String>,
+        _: &(),
+    ) -> Result<Reaction> {
+        let node = node::get();
+        if let Some(payload) = post_payload {
+            outbox::approve(
+                &node.config.storage,
+                &instance.concern,
+                instance.index,
+                payload,
+            )?;
+        }
+        let reaction = CartesiCompute::get_reaction(&node, instance, archive)?;
+        outbox::filter(
+            &node.config,
+            &instance.concern,
+            instance.index,
+            reaction,
+        )
+    }
 
     fn get_pretty_instance(
         instance: &state::Instance,
@@ -615,8 +1115,40 @@ impl DApp<()> for CartesiCompute {
                     &instance.json_data
                 )
             })?;
-        let ctx: CartesiComputeCtx = parsed.into();
-        let json_data = serde_json::to_string(&ctx).unwrap();
+        let ctx = CartesiComputeCtx::try_from(parsed)?;
+        let node = node::get();
+        let storage = &node.config.storage;
+        let verification =
+            results::load(storage, &instance.concern, instance.index)
+                .unwrap_or_else(|e| {
+                    warn!("Fail to load verification result: {}", e);
+                    None
+                });
+        let held = outbox::load(storage, &instance.concern, instance.index)
+            .unwrap_or_else(|e| {
+                warn!("Fail to load held transactions: {}", e);
+                vec![]
+            });
+        let pretty = PrettyCartesiComputeCtx {
+            ctx: &ctx,
+            verdict: verification.as_ref().map(|v| v.verdict()),
+            verification: verification,
+            template: templates::get_status(&node, ctx.template_hash),
+            rejections: policy::get_rejections(
+                &node.config.policy,
+                node.config.mode,
+                &ctx,
+                instance.concern.user_address,
+            ),
+            outbox: held,
+            time_left: if ctx.current_state.is_finished() {
+                None
+            } else {
+                schedule::get_time_left(&*node.clock, &ctx).ok()
+            },
+        };
+        let json_data = serde_json::to_string(&pretty)
+            .chain_err(|| "Could not serialize cartesi compute context")?;
 
         // get context (state) of the sub instances
 
@@ -625,9 +1157,11 @@ impl DApp<()> for CartesiCompute {
         let machine_id =
             build_machine_id(instance.index, &instance.concern.user_address);
         for sub in &instance.sub_instances {
-            pretty_sub_instances.push(Box::new(
-                VG::get_pretty_instance(sub, archive, &machine_id).unwrap(),
-            ))
+            pretty_sub_instances.push(Box::new(VG::get_pretty_instance(
+                sub,
+                archive,
+                &node.sessions.session_id(&machine_id),
+            )?))
         }
 
         let pretty_instance = state::Instance {
@@ -644,17 +1178,13 @@ impl DApp<()> for CartesiCompute {
 }
 
 fn abort_by_deadline_or_idle(
+    node: &Node,
     concern: &Concern,
     index: U256,
     deadline: u64,
 ) -> Result<Reaction> {
-    let current_time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .chain_err(|| "System time before UNIX_EPOCH")?
-        .as_secs();
-
     // if other party missed the deadline
-    if current_time > deadline {
+    if node.clock.now()? > deadline {
         info!("Aborting instance by time (index: {})", index);
         let request = TransactionRequest {
             contract_name: None, // Name not needed, is concern
@@ -672,317 +1202,512 @@ fn abort_by_deadline_or_idle(
     }
 }
 
-fn react_by_machine_output(
-    archive: &Archive,
+fn provide_drive(
+    node: &Node,
+    archive: &dyn ServiceClient,
     concern: &Concern,
     index: U256,
-    role: &Role,
-    input_drives: Vec<Drive>,
-    template_hash: H256,
-    claimed_final_hash: H256,
-    final_time: U256,
-    output_position: U256,
-    output_log2_size: U256,
-    machine_id: String,
-    noChallengeDrive: bool,
+    drive: &Drive,
 ) -> Result<Reaction> {
-    // create machine and fill in all the drives
-    let mut machine = cartesi_machine::MachineRequest::new();
-    machine.set_directory(format!(
-        "/opt/cartesi/srv/compute/cartesi-machine/{:x}",
-        template_hash
-    ));
-
-    let request = NewSessionRequest {
-        session_id: machine_id.clone(),
-        machine: machine,
-        force: true,
-    };
-
-    // send newSession request to the emulator service
-    let _processed_response: NewSessionResponse = archive
-        .get_response(
-            EMULATOR_SERVICE_NAME.to_string(),
-            machine_id.clone(),
-            EMULATOR_METHOD_NEW.to_string(),
-            request.into(),
-        )?
-        .into();
-
-    let mut drives_siblings = vec![];
-    let mut output_siblings = vec![];
-    let mut calculated_output = vec![];
-
-    let time = 0;
-    for drive in &input_drives {
-        let address = drive.position.as_u64();
-        let log2_size = drive.log2_size.as_u64();
-        if !drive.needs_logger {
-            // write direct values to drive
-            let data = drive.direct_value.clone();
-            let archive_key = build_session_write_key(
-                machine_id.clone(),
-                time,
-                address,
-                data.to_vec(),
-            );
-
-            let mut position = cartesi_machine::WriteMemoryRequest::new();
-            position.set_address(address);
-            position.set_data(data.to_vec());
-
-            let request = SessionWriteMemoryRequest {
-                session_id: machine_id.clone(),
-                time: time,
-                position: position,
-            };
-
-            let _processed_response = archive.get_response(
-                EMULATOR_SERVICE_NAME.to_string(),
-                archive_key,
-                EMULATOR_METHOD_WRITE.to_string(),
-                request.into(),
-            )?;
-        } else {
-            let drive_path = match get_ipfs_drive(
-                archive,
-                drive.ipfs_path.clone(),
-                drive.log2_size.as_u64() as u32,
-                drive.root_hash,
-            ) {
-                // try to get drive from Ipfs first
-                Ok(output_path) => output_path,
-                Err(e) => {
-                    if noChallengeDrive {
-                        return Err(e);
-                    }
-                    match e.kind() {
-                        ErrorKind::ResponseInvalidError(_service, _key, _m) => {
-                            // fall back to logger if drive not found in ipfs
-                            let request = DownloadFileRequest {
-                                root: drive.root_hash.clone(),
-                                path: format!("{:x}", drive.root_hash),
-                                page_log2_size: 3,
-                                tree_log2_size: drive.log2_size.as_u64(),
-                            };
-
-                            let processed_response: DownloadFileResponse =
-                                get_logger_response(
-                                    archive,
-                                    "CartesiCompute".into(),
-                                    build_logger_download_key(
-                                        drive.root_hash.clone(),
-                                    ),
-                                    LOGGER_METHOD_DOWNLOAD.to_string(),
-                                    request.into(),
-                                )?
-                                .into();
-                            trace!(
-                                "Downloaded! File stored at: {}...",
-                                processed_response.path
-                            );
-
-                            processed_response.path
-                        }
-                        _ => {
-                            return Err(e);
-                        }
-                    }
-                }
-            };
-
-            let data_len = std::fs::metadata(drive_path.clone())?.len();
-            let archive_key = build_session_replace_key(
-                machine_id.clone(),
-                time,
-                address,
-                drive_path.clone(),
+    let storage = &node.config.storage;
+    let inbox_path = build_drive_inbox_path(
+        &storage.inbox_dir(),
+        concern,
+        index,
+        drive.position,
+    );
+
+    let content = match std::fs::read(&inbox_path) {
+        Ok(content) => content,
+        Err(e) => {
+            warn!(
+                "Drive content for Cartesi Compute (index: {}) not available at {}: {}",
+                index, inbox_path, e
             );
-
-            let mut mrc = cartesi_machine::MemoryRangeConfig::new();
-            
-            mrc.set_start(address);
-            mrc.set_length(1 << (drive.log2_size.as_u64() as u32));
-            mrc.set_image_filename(drive_path.clone());
-            mrc.set_shared(false);
-
-            let request = SessionReplaceMemoryRangeRequest {
-                session_id: machine_id.clone(),
-                time: time,
-                range: mrc,
-            };
-
-            let _ = archive.get_response(
-                EMULATOR_SERVICE_NAME.to_string(),
-                archive_key,
-                EMULATOR_METHOD_REPLACE.to_string(),
-                request.into(),
-            )?;
+            return Ok(Reaction::Idle);
         }
-        if let Role::Claimer = role {
-            // get input drive siblings
-            let archive_key = build_session_proof_key(
-                machine_id.clone(),
-                time,
-                address,
-                log2_size,
-            );
-            let mut target = cartesi_machine::GetProofRequest::new();
-            target.set_address(address);
-            target.set_log2_size(log2_size);
-
-            let request = SessionGetProofRequest {
-                session_id: machine_id.clone(),
-                time: time,
-                target: target,
-            };
+    };
 
-            let processed_response: SessionGetProofResponse = archive
-                .get_response(
-                    EMULATOR_SERVICE_NAME.to_string(),
-                    archive_key,
-                    EMULATOR_METHOD_PROOF.to_string(),
-                    request.into(),
-                )?
-                .into();
-
-            trace!("Get proof result: {:?}...", processed_response.proof);
-
-            // get actual siblings
-            let mut drive_siblings: Vec<_> = processed_response
-                .proof
-                .sibling_hashes
-                .into_iter()
-                .map(|hash| Token::FixedBytes(hash.0.to_vec()))
-                .collect();
-            trace!("Size of siblings: {}", drive_siblings.len());
-            // !!!!! This should not be necessary, !!!!!!!
-            // !!!!! the emulator should do it     !!!!!!!
-            drive_siblings.reverse();
+    let log2_size = drive.log2_size.as_u64();
+    if content.len() as u64 > (1 << log2_size) {
+        return Err(format!(
+            "Drive content at {} has {} bytes, exceeding log2 size {}",
+            inbox_path,
+            content.len(),
+            log2_size
+        )
+        .into());
+    }
 
-            drives_siblings.push(Token::Array(drive_siblings));
-        }
+    if !drive.needs_logger {
+        info!("Providing direct drive (index: {})", index);
+        let request = TransactionRequest {
+            contract_name: None, // Name not needed, is concern
+            concern: concern.clone(),
+            value: U256::from(0),
+            function: "provideDirectDrive".into(),
+            data: vec![Token::Uint(index), Token::Bytes(content)],
+            gas: None,
+            strategy: transaction::Strategy::Simplest,
+        };
+        return Ok(Reaction::Transaction(request));
     }
 
-    let time = final_time.as_u64();
-    let sample_points: Vec<u64> = vec![0, time];
+    // what the logger reports must be the root hash of the inbox file,
+    // not of a stale copy or of whatever the logger had under that name
+    let inbox_root = node
+        .verified_drives
+        .root_hash(&inbox_path, log2_size)
+        .chain_err(|| format!("Could not hash drive content {}", inbox_path))?;
+
+    // the logger reads files relative to the shared drives directory
+    let file_name = format!("{}_{:x}", index, drive.position);
+    let file_path = storage.drive_path(&file_name);
+    if !Path::new(&file_path).exists()
+        || node.verified_drives.root_hash(&file_path, log2_size)? != inbox_root
+    {
+        std::fs::copy(&inbox_path, &file_path)?;
+    }
 
-    let request = SessionRunRequest {
-        session_id: machine_id.clone(),
-        times: sample_points.clone(),
+    let request = SubmitFileRequest {
+        path: storage.drive_name(&file_name),
+        page_log2_size: 3,
+        tree_log2_size: log2_size,
     };
-    let archive_key =
-        build_session_run_key(machine_id.clone(), sample_points.clone());
 
-    let processed_result: SessionRunResult = get_run_result(
+    let processed_response: SubmitFileResponse = get_logger_response(
         archive,
-        "CartesiCompute".to_string(),
-        archive_key,
+        "CartesiCompute".into(),
+        build_logger_provide_key(file_name),
+        LOGGER_METHOD_SUBMIT.to_string(),
         request.into(),
-    )?;
+    )?
+    .into();
+    let root = processed_response.root;
+    trace!("Submitted provider drive with hash: {:x}...", root);
+    if root != inbox_root {
+        return Err(format!(
+            "Logger submitted {} with root hash {:x}, expected {:x}",
+            file_path, root, inbox_root
+        )
+        .into());
+    }
 
-    let calculated_final_hash = processed_result.hashes[1];
+    // keep a copy named after the root hash, where reveals look for it
+    let root_path = storage.drive_path(&format!("{:x}", root));
+    if !Path::new(&root_path).exists() {
+        std::fs::copy(&file_path, &root_path)?;
+    }
 
-    if let Role::Claimer = role {
-        // get output value
-        let log2_size = output_log2_size.as_u64();
-        let length = 2_u64.pow(log2_size as u32);
-        let address = output_position.as_u64();
-
-        let archive_key =
-            build_session_read_key(machine_id.clone(), time, address, length);
-        let mut position = cartesi_machine::ReadMemoryRequest::new();
-        position.set_address(address);
-        position.set_length(length);
-
-        let request = SessionReadMemoryRequest {
-            session_id: machine_id.clone(),
-            time: time,
-            position: position,
-        };
+    info!("Providing logger drive (index: {})", index);
+    let request = TransactionRequest {
+        contract_name: None, // Name not needed, is concern
+        concern: concern.clone(),
+        value: U256::from(0),
+        function: "provideLoggerDrive".into(),
+        data: vec![
+            Token::Uint(index),
+            Token::FixedBytes(root.to_fixed_bytes().to_vec()),
+        ],
+        gas: None,
+        strategy: transaction::Strategy::Simplest,
+    };
+    return Ok(Reaction::Transaction(request));
+}
 
-        let processed_response: SessionReadMemoryResponse = archive
-            .get_response(
-                EMULATOR_SERVICE_NAME.to_string(),
-                archive_key,
-                EMULATOR_METHOD_READ.to_string(),
-                request.into(),
-            )?
-            .into();
+/// Role of `user_address`, whose party state `ctx` holds. The challenger
+/// is only known while a challenge is being decided, and a party that
+/// lost a dispute has voted for good.
+fn get_role(user_address: Address, ctx: &CartesiComputeCtx) -> Role {
+    let party = &ctx.partyState;
+    if !party.isParty {
+        return Role::Observer;
+    }
+    if user_address == ctx.claimer {
+        return Role::Claimer;
+    }
+    if ctx.current_state == State::WaitingChallengeResult
+        && is_challenger(user_address, party, ctx)
+    {
+        return Role::Challenger;
+    }
+    if party.hasVoted || party.hasCheated {
+        return Role::Voted;
+    }
+    Role::Voter
+}
 
-        trace!(
-            "Read memory result: {:?}...",
-            processed_response.read_content.data
-        );
+/// Whether the party of `user_address` challenged the claim being
+/// decided. getState reports the zero address for a challenger at index
+/// 0, so that one is recognised by its index.
+fn is_challenger(
+    user_address: Address,
+    party: &Party,
+    ctx: &CartesiComputeCtx,
+) -> bool {
+    if !party.hasVoted {
+        return false;
+    }
+    if ctx.challenger == Address::zero() {
+        return party.arrayIdx.is_zero();
+    }
+    user_address == ctx.challenger
+}
 
-        calculated_output = processed_response.read_content.data.clone();
+/// Image of a drive kept off-chain, stored in `dir` under its root hash
+/// the way the node stores the drives it downloads
+pub fn get_local_drive_image(
+    node: &Node,
+    dir: &str,
+    drive: &Drive,
+) -> Result<String> {
+    let path = format!("{}/{:x}", dir, drive.root_hash);
+    if !node
+        .verified_drives
+        .verify(&path, drive.log2_size.as_u64(), drive.root_hash)
+        .chain_err(|| format!("Could not check drive image {}", path))?
+    {
+        return Err(format!(
+            "Drive image {} doesn't match its root hash {:x}",
+            path, drive.root_hash
+        )
+        .into());
+    }
+    Ok(path)
+}
 
-        let archive_key = build_session_proof_key(
-            machine_id.clone(),
-            time,
-            address,
-            log2_size,
-        );
-        let mut target = cartesi_machine::GetProofRequest::new();
-        target.set_address(address);
-        target.set_log2_size(log2_size);
-
-        let request = SessionGetProofRequest {
-            session_id: machine_id.clone(),
-            time: time,
-            target: target,
-        };
+/// Our machine run to the final time of an instance
+struct MachineOutput {
+    session_id: String,
+    /// Proofs of the input drives in the initial machine
+    drive_proofs: Vec<ContractProof>,
+    final_hash: H256,
+    output: Vec<u8>,
+}
 
-        let processed_response: SessionGetProofResponse = archive
-            .get_response(
-                EMULATOR_SERVICE_NAME.to_string(),
-                archive_key,
-                EMULATOR_METHOD_PROOF.to_string(),
-                request.into(),
-            )?
-            .into();
+/// Run the machine of the instance to `final_time`, after checking it
+/// starts as the contract expects, and read its output
+fn compute_machine_output(
+    node: &Node,
+    emulator: &dyn EmulatorBackend,
+    get_drive_image: &dyn Fn(&Drive) -> Result<String>,
+    index: U256,
+    input_drives: &Vec<Drive>,
+    template_hash: H256,
+    initial_hash: H256,
+    final_time: U256,
+    output_position: U256,
+    output_log2_size: U256,
+    machine_id: &str,
+) -> Result<MachineOutput> {
+    with_session(
+        node,
+        emulator,
+        get_drive_image,
+        machine_id,
+        template_hash,
+        input_drives,
+        &|session: &session::Session| {
+            let session_id = session.session_id.clone();
+            let drives = session.drives.clone().unwrap_or(vec![]);
+
+            // never run a machine other than the one the contract expects
+            let initial_hashes = emulator.run(&session_id, vec![0])?;
+            let drive_proofs = check_initial_hash(
+                index,
+                template_hash,
+                initial_hash,
+                input_drives,
+                &drives,
+                initial_hashes[0],
+            )?;
 
-        trace!("Get proof result: {:?}...", processed_response.proof);
+            let time = final_time.as_u64();
+            let final_hashes = emulator.run(&session_id, vec![time])?;
 
-        // get actual siblings
-        output_siblings = processed_response
-            .proof
-            .sibling_hashes
-            .into_iter()
-            .map(|hash| Token::FixedBytes(hash.0.to_vec()))
-            .collect();
-        trace!("Size of siblings: {}", output_siblings.len());
-        // !!!!! This should not be necessary, !!!!!!!
-        // !!!!! the emulator should do it     !!!!!!!
-        output_siblings.reverse();
-    }
+            let length = 2_u64.pow(output_log2_size.as_u32());
+            let output = emulator.read(
+                &session_id,
+                time,
+                output_position.as_u64(),
+                length,
+            )?;
+
+            Ok(MachineOutput {
+                session_id: session_id,
+                drive_proofs: drive_proofs,
+                final_hash: final_hashes[0],
+                output: output,
+            })
+        },
+    )
+}
+
+/// Do the machine work of the instance once it is admitted, nearest
+/// deadlines first
+fn run_scheduled<F>(
+    node: &Node,
+    machine_id: &str,
+    deadline: U256,
+    work: F,
+) -> Result<Reaction>
+where
+    F: FnOnce() -> Result<Reaction>,
+{
+    let admission = match node.scheduler.admit(machine_id, deadline.as_u64()) {
+        Some(admission) => admission,
+        None => {
+            info!(
+                "Machine {} waits for instances with nearer deadlines",
+                machine_id
+            );
+            return Ok(Reaction::Idle);
+        }
+    };
+    let reaction = work();
+    admission.finish(&reaction);
+    reaction
+}
+
+/// Run the machine of the instance before its output is due, so the
+/// output is ready by then. Failures are left for when it is due.
+fn run_early(
+    node: &Node,
+    emulator: &dyn EmulatorBackend,
+    get_drive_image: &dyn Fn(&Drive) -> Result<String>,
+    index: U256,
+    ctx: &CartesiComputeCtx,
+    machine_id: &str,
+) {
+    let admission =
+        match node.scheduler.admit(machine_id, ctx.deadline.as_u64()) {
+            Some(admission) => admission,
+            None => return,
+        };
+    let output = compute_machine_output(
+        node,
+        emulator,
+        get_drive_image,
+        index,
+        &ctx.input_drives,
+        ctx.template_hash,
+        ctx.initial_hash,
+        ctx.final_time,
+        ctx.output_position,
+        ctx.output_log2_size,
+        machine_id,
+    );
+    admission.finish(&output);
+    match output {
+        Ok(_) => trace!("Machine {} ran ahead of time", machine_id),
+        Err(e) => trace!("Machine {} not run ahead of time: {}", machine_id, e),
+    }
+}
+
+/// Start downloading the drives whose content is already known, so they
+/// are here by the time the machine needs them
+fn prefetch_drives(
+    fetch_drive: &dyn Fn(&Drive, bool) -> Result<String>,
+    ctx: &CartesiComputeCtx,
+    user_address: Address,
+) {
+    for drive in &ctx.input_drives {
+        if !drive.needs_logger
+            || drive.provider == user_address
+            || drive.ipfs_path == ""
+            || drive.root_hash == H256::zero()
+        {
+            continue;
+        }
+        if let Err(e) = fetch_drive(drive, true) {
+            trace!("Drive {:x} not prefetched yet: {}", drive.root_hash, e);
+        }
+    }
+}
+
+/// Verify the claim of the instance, unless it was verified already,
+/// and record the verdict without ever reacting to it
+fn watch(
+    node: &Node,
+    emulator: &dyn EmulatorBackend,
+    get_drive_image: &dyn Fn(&Drive) -> Result<String>,
+    concern: &Concern,
+    index: U256,
+    ctx: CartesiComputeCtx,
+    machine_id: String,
+) -> Result<Reaction> {
+    if !ctx.current_state.has_claim() {
+        if ctx.current_state == State::WaitingClaim {
+            run_early(
+                node,
+                emulator,
+                get_drive_image,
+                index,
+                &ctx,
+                &machine_id,
+            );
+        }
+        return Ok(Reaction::Idle);
+    }
+    match results::load(&node.config.storage, concern, index) {
+        Ok(Some(verification)) => {
+            if verification
+                .is_of_claim(ctx.claimed_final_hash, &ctx.claimed_output)
+            {
+                return Ok(Reaction::Idle);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Fail to load verification result: {}", e),
+    }
+    let admission =
+        match node.scheduler.admit(&machine_id, ctx.deadline.as_u64()) {
+            Some(admission) => admission,
+            None => return Ok(Reaction::Idle),
+        };
+
+    let output = compute_machine_output(
+        node,
+        emulator,
+        get_drive_image,
+        index,
+        &ctx.input_drives,
+        ctx.template_hash,
+        ctx.initial_hash,
+        ctx.final_time,
+        ctx.output_position,
+        ctx.output_log2_size,
+        &machine_id,
+    );
+    admission.finish(&output);
+    let output = output?;
+    let verification = results::Verification::new(
+        index,
+        output.final_hash,
+        ctx.claimed_final_hash,
+        output.output,
+        ctx.claimed_output,
+    );
+    results::store(&node.config.storage, concern, &verification)?;
+    info!(
+        "Claim of Cartesi Compute (index: {}) verified: {:?}",
+        index,
+        verification.verdict()
+    );
+
+    // nothing else is run on the machine of a verified claim
+    node.sessions.end(emulator, &machine_id)?;
+    Ok(Reaction::Idle)
+}
+
+fn react_by_machine_output(
+    node: &Node,
+    emulator: &dyn EmulatorBackend,
+    get_drive_image: &dyn Fn(&Drive) -> Result<String>,
+    concern: &Concern,
+    index: U256,
+    role: &Role,
+    input_drives: Vec<Drive>,
+    template_hash: H256,
+    initial_hash: H256,
+    claimed_final_hash: H256,
+    claimed_output: Vec<u8>,
+    final_time: U256,
+    output_position: U256,
+    output_log2_size: U256,
+    machine_id: String,
+) -> Result<Reaction> {
+    let MachineOutput {
+        session_id,
+        drive_proofs,
+        final_hash: calculated_final_hash,
+        output: calculated_output,
+    } = compute_machine_output(
+        node,
+        emulator,
+        get_drive_image,
+        index,
+        &input_drives,
+        template_hash,
+        initial_hash,
+        final_time,
+        output_position,
+        output_log2_size,
+        &machine_id,
+    )?;
+
+    let time = final_time.as_u64();
+    let log2_size = output_log2_size.as_u64();
+    let address = output_position.as_u64();
+
+    let mut output_proof = None;
+    if let Role::Claimer = role {
+        let proof = emulator.proof(&session_id, time, address, log2_size)?;
+        let proof = ContractProof::new(&proof, address, log2_size)?;
+        // the contract checks the output against the final hash
+        proof.check(
+            merkle::get_root_hash(
+                &calculated_output,
+                merkle::WORD_LOG2_SIZE,
+                log2_size,
+            )?,
+            calculated_final_hash,
+        )?;
+        trace!("Size of siblings: {}", proof.siblings.len());
+        output_proof = Some(proof);
+    }
 
     match role {
         Role::Claimer => {
             info!("Claiming output (index: {})", index);
+            let output_proof =
+                output_proof.ok_or(Error::from("Output proof not taken"))?;
+            let data = vec![
+                Token::Uint(index),
+                Token::FixedBytes(
+                    calculated_final_hash.to_fixed_bytes().to_vec(),
+                ),
+                Token::Array(
+                    drive_proofs.iter().map(|p| p.to_token()).collect(),
+                ),
+                Token::Bytes(calculated_output.clone()),
+                output_proof.to_token(),
+            ];
+            let gas = gas::estimate_submit_claim(node, concern, &data)?;
             let request = TransactionRequest {
                 contract_name: None, // Name not needed, is concern
                 concern: concern.clone(),
                 value: U256::from(0),
                 function: "submitClaim".into(),
-                data: vec![
-                    Token::Uint(index),
-                    Token::FixedBytes(
-                        calculated_final_hash.to_fixed_bytes().to_vec(),
-                    ),
-                    Token::Array(drives_siblings),
-                    Token::Bytes(calculated_output),
-                    Token::Array(output_siblings),
-                ],
-                // TODO: change back to None after done testing
-                gas: Some(U256::from(628318)),
+                data: data,
+                gas: Some(gas),
                 strategy: transaction::Strategy::Simplest,
             };
             return Ok(Reaction::Transaction(request));
         }
-        Role::Other => {
+        Role::Voter => {
+            let verification = results::Verification::new(
+                index,
+                calculated_final_hash,
+                claimed_final_hash,
+                calculated_output,
+                claimed_output,
+            );
+            results::store(&node.config.storage, concern, &verification)?;
+
+            // the output was checked against the final hash on claim,
+            // a challenge would only be decided on the final hash
+            if verification.final_hash_matches && !verification.output_matches
+            {
+                error!(
+                    "Claimed output of Cartesi Compute (index: {}) doesn't match the machine output, not voting",
+                    index
+                );
+                return Ok(Reaction::Idle);
+            }
+
             let function = {
-                if calculated_final_hash == claimed_final_hash {
+                if verification.final_hash_matches {
                     String::from("confirm")
                 } else {
                     String::from("challenge")
@@ -1000,18 +1725,328 @@ fn react_by_machine_output(
             };
             return Ok(Reaction::Transaction(request));
         }
-        _ => {
-            error!("Challenger shouldn't get here!");
-            return Ok(Reaction::Idle); //@dev this shouldnt happen, shoud we explode here? how?
+        Role::Challenger | Role::Voted | Role::Observer => {
+            return Err(format!(
+                "Cartesi Compute (index: {}) has no reaction to the machine output as {:?}",
+                index, role
+            )
+            .into());
+        }
+    }
+}
+
+/// Pass `use_session` the emulator session of the instance with every
+/// input drive mounted, creating it unless one was built from the same
+/// inputs. A reused session is taken as lost if the emulator answers
+/// `use_session` as invalid, and `use_session` is passed a rebuilt one.
+/// Drives kept off-chain are mounted from the image `get_drive_image`
+/// gives for them.
+fn with_session<T>(
+    node: &Node,
+    emulator: &dyn EmulatorBackend,
+    get_drive_image: &dyn Fn(&Drive) -> Result<String>,
+    machine_id: &str,
+    template_hash: H256,
+    input_drives: &Vec<Drive>,
+    use_session: &dyn Fn(&session::Session) -> Result<T>,
+) -> Result<T> {
+    for drive in input_drives {
+        drive.check_range()?;
+    }
+
+    let inputs = get_session_inputs(template_hash, input_drives)?;
+    if let Some(session) = node.sessions.get_prepared(machine_id, inputs) {
+        match use_session(&session) {
+            Err(e) => match e.kind() {
+                ErrorKind::ResponseInvalidError(..) => warn!(
+                    "Emulator lost session {}, rebuilding it",
+                    session.session_id
+                ),
+                _ => return Err(e),
+            },
+            result => return result,
         }
+        node.sessions.lost(machine_id);
     }
+    let session = prepare_session(
+        node,
+        emulator,
+        get_drive_image,
+        machine_id,
+        template_hash,
+        inputs,
+        input_drives,
+    )?;
+    use_session(&session)
+}
+
+/// Create the emulator session of the instance, built from `inputs`,
+/// and mount every input drive
+fn prepare_session(
+    node: &Node,
+    emulator: &dyn EmulatorBackend,
+    get_drive_image: &dyn Fn(&Drive) -> Result<String>,
+    machine_id: &str,
+    template_hash: H256,
+    inputs: H256,
+    input_drives: &Vec<Drive>,
+) -> Result<session::Session> {
+    let session_id = node.sessions.begin(emulator, machine_id, inputs)?;
+
+    // create machine and fill in all the drives
+    emulator.new_session(&session_id, template_hash)?;
+
+    let mut drives = vec![];
+
+    let time = 0;
+    for drive in input_drives {
+        let address = drive.position.as_u64();
+        let log2_size = drive.log2_size.as_u64();
+        if !drive.needs_logger {
+            // write direct values to drive, zero filling the rest of the
+            // range as the contract does when hashing it
+            emulator.write(
+                &session_id,
+                time,
+                address,
+                log2_size,
+                drive.direct_value.clone(),
+            )?;
+        } else {
+            let drive_path = get_drive_image(drive)?;
+            emulator.replace(
+                &session_id,
+                time,
+                address,
+                log2_size,
+                &drive_path,
+            )?;
+        }
+        // get input drive siblings now, the contract expects them
+        // taken before the next drives are mounted
+        drives.push(emulator.proof(&session_id, time, address, log2_size)?);
+    }
+
+    node.sessions.finish(machine_id, drives);
+    node.sessions
+        .get_prepared(machine_id, inputs)
+        .ok_or(Error::from(format!(
+            "Emulator session {} was not kept",
+            session_id
+        )))
+}
+
+/// Get the image of a drive kept off-chain, from Ipfs or, failing that,
+/// from the logger
+fn fetch_drive_image(
+    node: &Node,
+    archive: &dyn ServiceClient,
+    drive: &Drive,
+    noChallengeDrive: bool,
+) -> Result<String> {
+    let log2_size = drive.log2_size.as_u64();
+    match get_ipfs_drive(
+        node,
+        archive,
+        drive.ipfs_path.clone(),
+        log2_size as u32,
+        drive.root_hash,
+        drive.download_as_car,
+    ) {
+        // try to get drive from Ipfs first
+        Ok(output_path) => Ok(output_path),
+        Err(e) => {
+            if noChallengeDrive {
+                return Err(e);
+            }
+            match e.kind() {
+                ErrorKind::ResponseInvalidError(_service, _key, _m) => {
+                    // fall back to logger if drive not found in ipfs
+                    let request = DownloadFileRequest {
+                        root: drive.root_hash.clone(),
+                        path: node
+                            .config
+                            .storage
+                            .drive_name(&format!("{:x}", drive.root_hash)),
+                        page_log2_size: 3,
+                        tree_log2_size: log2_size,
+                    };
+
+                    let processed_response: DownloadFileResponse =
+                        get_logger_response(
+                            archive,
+                            "CartesiCompute".into(),
+                            build_logger_download_key(drive.root_hash.clone()),
+                            LOGGER_METHOD_DOWNLOAD.to_string(),
+                            request.into(),
+                        )?
+                        .into();
+                    trace!(
+                        "Downloaded! File stored at: {}...",
+                        processed_response.path
+                    );
+
+                    if !node.verified_drives.verify(
+                        &processed_response.path,
+                        log2_size,
+                        drive.root_hash,
+                    )? {
+                        return Err(format!(
+                            "Drive at position {:x} downloaded from logger to {} doesn't match its root hash {:x}",
+                            drive.position,
+                            processed_response.path,
+                            drive.root_hash
+                        )
+                        .into());
+                    }
+
+                    Ok(processed_response.path)
+                }
+                _ => Err(e),
+            }
+        }
+    }
+}
+
+/// Hash of everything that determines the initial hash of the machine:
+/// the template and the range and content of every drive
+fn get_session_inputs(
+    template_hash: H256,
+    input_drives: &Vec<Drive>,
+) -> Result<H256> {
+    let mut inputs = template_hash.as_bytes().to_vec();
+    for drive in input_drives {
+        let drive_hash = get_drive_hash(drive)?;
+        let mut word = [0u8; 32];
+        drive.position.to_big_endian(&mut word);
+        inputs.extend_from_slice(&word);
+        drive.log2_size.to_big_endian(&mut word);
+        inputs.extend_from_slice(&word);
+        inputs.extend_from_slice(drive_hash.as_bytes());
+    }
+    Ok(keccak(inputs))
+}
+
+/// Hash of a drive as the contract records it
+fn get_drive_hash(drive: &Drive) -> Result<H256> {
+    if drive.needs_logger {
+        Ok(drive.root_hash)
+    } else {
+        merkle::get_root_hash(
+            &drive.direct_value,
+            merkle::WORD_LOG2_SIZE,
+            drive.log2_size.as_u64(),
+        )
+    }
+}
+
+/// Check the machine hash at time 0, with every drive mounted, against
+/// the initial hash of the contract, reporting the drive most likely
+/// responsible for a mismatch. Until a claim is submitted the contract
+/// holds the template hash, so the expected hash is computed the way
+/// `submitClaim` will, mounting each drive over the template. Returns
+/// the verified proofs of the drives.
+fn check_initial_hash(
+    index: U256,
+    template_hash: H256,
+    initial_hash: H256,
+    input_drives: &Vec<Drive>,
+    drives: &Vec<Proof>,
+    machine_hash: H256,
+) -> Result<Vec<ContractProof>> {
+    let mismatch = |culprit: String| -> Error {
+        error!(
+            "Initial hash mismatch for Cartesi Compute (index: {}): {}",
+            index, culprit
+        );
+        format!(
+            "Machine of Cartesi Compute (index: {}) doesn't match its initial hash: {}",
+            index, culprit
+        )
+        .into()
+    };
+
+    if input_drives.len() != drives.len() {
+        return Err(mismatch(format!(
+            "{} drives mounted out of {}",
+            drives.len(),
+            input_drives.len()
+        )));
+    }
+
+    let mut proofs = vec![];
+    for (drive, proof) in input_drives.iter().zip(drives.iter()) {
+        let position = drive.position.as_u64();
+        let proof =
+            ContractProof::new(proof, position, drive.log2_size.as_u64())
+                .map_err(|e| {
+                    mismatch(format!(
+                        "drive at position {:x} has an invalid proof: {}",
+                        position, e
+                    ))
+                })?;
+        proofs.push(proof);
+    }
+
+    let claimed = initial_hash != template_hash;
+    if claimed && machine_hash == initial_hash {
+        return Ok(proofs);
+    }
+
+    let mut expected = template_hash;
+    for (drive, proof) in input_drives.iter().zip(proofs.iter()) {
+        let position = drive.position.as_u64();
+        let log2_size = drive.log2_size.as_u64();
+        let drive_hash = get_drive_hash(drive)?;
+        if proof.target_hash != drive_hash {
+            return Err(mismatch(format!(
+                "drive at position {:x} was mounted with hash {:x}, expected {:x}",
+                position, proof.target_hash, drive_hash
+            )));
+        }
+
+        let pristine = merkle::get_root_with_drive(
+            position,
+            log2_size,
+            merkle::get_pristine_hash(log2_size)?,
+            &proof.siblings,
+        )?;
+        if pristine != expected {
+            return Err(mismatch(format!(
+                "machine around drive at position {:x} differs from the template and previous drives",
+                position
+            )));
+        }
+        expected = merkle::get_root_with_drive(
+            position,
+            log2_size,
+            drive_hash,
+            &proof.siblings,
+        )?;
+    }
+
+    if machine_hash != expected {
+        return Err(mismatch(format!(
+            "machine hash {:x} differs from {:x} given by the template and drives",
+            machine_hash, expected
+        )));
+    }
+    if claimed {
+        return Err(mismatch(format!(
+            "template and drives give {:x} but the contract recorded {:x}",
+            expected, initial_hash
+        )));
+    }
+    Ok(proofs)
 }
 
 fn get_ipfs_drive(
-    archive: &Archive,
+    node: &Node,
+    archive: &dyn ServiceClient,
     ipfs_path: String,
     log2_size: u32,
     root_hash: H256,
+    download_as_car: bool,
 ) -> std::result::Result<String, Error> {
     let key = build_ipfs_get_key(ipfs_path.clone());
 
@@ -1026,15 +2061,30 @@ fn get_ipfs_drive(
         return Err(invalid_error);
     }
 
-    let request = GetFileRequest {
-        ipfs_path,
-        log2_size,
-        output_path: format!(
-            "/opt/cartesi/srv/compute/flashdrive/{:x}",
-            root_hash
-        ),
-        // TODO: come up with better timeout
-        timeout: 120,
+    let drive_path =
+        node.config.storage.drive_path(&format!("{:x}", root_hash));
+    // CAR drives are only moved to drive_path once unpacked and verified
+    if download_as_car && Path::new(&drive_path).exists() {
+        return Ok(drive_path);
+    }
+
+    let request = if download_as_car {
+        GetFileRequest {
+            ipfs_path,
+            // the archive carries some overhead over the raw drive
+            log2_size: car::get_archive_log2_size(log2_size),
+            output_path: format!("{}.car", drive_path),
+            // TODO: come up with better timeout
+            timeout: 120,
+        }
+    } else {
+        GetFileRequest {
+            ipfs_path,
+            log2_size,
+            output_path: drive_path.clone(),
+            // TODO: come up with better timeout
+            timeout: 120,
+        }
     };
 
     match archive.get_response(
@@ -1044,7 +2094,11 @@ fn get_ipfs_drive(
         request.clone().into(),
     ) {
         Ok(data) => {
-            let response: GetFileResponse = data.into();
+            // a malformed response is the service's fault, not the drive's,
+            // so it must not be reported as ResponseInvalidError
+            let response = GetFileResponse::try_from(data).chain_err(|| {
+                format!("Malformed ipfs response for {}", key)
+            })?;
             info!("Response received from Ipfs {:?}", response);
 
             match response.one_of {
@@ -1061,7 +2115,31 @@ fn get_ipfs_drive(
                     )))
                 }
                 GetFileResponseOneOf::GetResult(r) => {
-                    if r.root_hash != root_hash {
+                    if download_as_car {
+                        // the root hash reported refers to the archive,
+                        // check the unpacked drive instead
+                        let unpacked_path = format!("{}.unpacked", drive_path);
+                        car::unpack_car_file(
+                            &r.output_path,
+                            &unpacked_path,
+                            log2_size as u64,
+                        )?;
+                        if !node.verified_drives.verify(
+                            &unpacked_path,
+                            log2_size as u64,
+                            root_hash,
+                        )? {
+                            info!("Root hash mismatch for CAR drive");
+                            std::fs::remove_file(&unpacked_path)?;
+                            return Err(invalid_error);
+                        }
+                        std::fs::rename(&unpacked_path, &drive_path)?;
+                        Ok(drive_path)
+                    } else if !node.verified_drives.verify(
+                        &r.output_path,
+                        log2_size as u64,
+                        root_hash,
+                    )? {
                         info!("Root hash mismatch");
                         Err(invalid_error)
                     } else {
@@ -1073,3 +2151,703 @@ fn get_ipfs_drive(
         Err(e) => Err(e),
     }
 }
+
+fn add_ipfs_drive(
+    archive: &dyn ServiceClient,
+    file_path: String,
+) -> std::result::Result<String, Error> {
+    let key = build_ipfs_add_key(file_path.clone());
+
+    let request = AddFileRequest { file_path };
+
+    let data = archive.get_response(
+        IPFS_SERVICE_NAME.into(),
+        key.clone(),
+        IPFS_METHOD_ADD.into(),
+        request.clone().into(),
+    )?;
+    let response = AddFileResponse::try_from(data)
+        .chain_err(|| format!("Malformed ipfs response for {}", key))?;
+    info!("Response received from Ipfs {:?}", response);
+
+    match response.one_of {
+        AddFileResponseOneOf::AddProgress(p) => {
+            Err(Error::from(ErrorKind::ServiceNeedsRetry(
+                IPFS_SERVICE_NAME.to_string(),
+                key,
+                IPFS_METHOD_ADD.into(),
+                request.into(),
+                "CartesiCompute".into(),
+                1,
+                p.progress,
+                "IPFS still adding".to_string(),
+            )))
+        }
+        AddFileResponseOneOf::AddResult(r) => Ok(r.ipfs_path),
+    }
+}
+
+/// How many drive images `VerifiedDrives` remembers at most
+const MAX_VERIFIED_DRIVES: usize = 256;
+
+/// Root hashes of the drive images already hashed, each kept only while
+/// the image has the modification time and length it was hashed with
+#[derive(Default)]
+pub struct VerifiedDrives {
+    drives: Mutex<HashMap<String, HashedDrive>>,
+}
+
+struct HashedDrive {
+    modified: SystemTime,
+    len: u64,
+    log2_size: u64,
+    root_hash: H256,
+    last_used: Instant,
+}
+
+impl VerifiedDrives {
+    /// Check the drive image at `path` against the expected root hash
+    pub fn verify(
+        &self,
+        path: &str,
+        log2_size: u64,
+        root_hash: H256,
+    ) -> Result<bool> {
+        let calculated_root_hash = self.root_hash(path, log2_size)?;
+        if calculated_root_hash != root_hash {
+            warn!(
+                "Drive image {} has root hash {:x}, expected {:x}",
+                path, calculated_root_hash, root_hash
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Root hash of the drive image at `path`, hashing it again only if
+    /// it changed since it was last hashed
+    pub fn root_hash(&self, path: &str, log2_size: u64) -> Result<H256> {
+        let metadata = std::fs::metadata(path)
+            .chain_err(|| format!("Could not read drive image {}", path))?;
+        let modified = metadata.modified()?;
+        let len = metadata.len();
+
+        if let Some(hashed) = self.drives.lock().unwrap().get_mut(path) {
+            if hashed.modified == modified
+                && hashed.len == len
+                && hashed.log2_size == log2_size
+            {
+                hashed.last_used = Instant::now();
+                return Ok(hashed.root_hash);
+            }
+        }
+
+        let root_hash = merkle::get_file_root_hash(
+            path,
+            merkle::WORD_LOG2_SIZE,
+            log2_size,
+        )?;
+
+        let mut drives = self.drives.lock().unwrap();
+        if drives.len() >= MAX_VERIFIED_DRIVES && !drives.contains_key(path) {
+            let oldest = drives
+                .iter()
+                .min_by_key(|(_, hashed)| hashed.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                drives.remove(&oldest);
+            }
+        }
+        drives.insert(
+            path.to_string(),
+            HashedDrive {
+                modified: modified,
+                len: len,
+                log2_size: log2_size,
+                root_hash: root_hash,
+                last_used: Instant::now(),
+            },
+        );
+        Ok(root_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::clock::ManualClock;
+    use super::super::compute::SessionRunResult;
+    use super::super::config::ConcernConfig;
+    use super::super::emulator::MockEmulator;
+    use super::super::gas::{FixedGasEstimator, NoGasEstimator};
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    const DRIVE_POSITION: u64 = 1 << 32;
+    const OUTPUT_POSITION: u64 = 1 << 33;
+    const LOG2_SIZE: u64 = 5;
+    const FINAL_TIME: u64 = 1000;
+    const GAS_ESTIMATE: u64 = 100_000;
+
+    fn get_concern() -> Concern {
+        Concern {
+            contract_address: Address::from_low_u64_be(1),
+            user_address: Address::from_low_u64_be(2),
+        }
+    }
+
+    /// Contract abi shipped for `network`
+    fn get_deployment_abi(network: &str) -> String {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../deployments")
+            .join(network)
+            .join("CartesiCompute.json")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn get_node(clock: Arc<ManualClock>) -> Node {
+        Node::new(ComputeConfig::default(), clock, Arc::new(NoGasEstimator))
+    }
+
+    #[test]
+    fn waits_until_the_deadline_has_passed() {
+        let clock = Arc::new(ManualClock::new(100));
+        let node = get_node(clock.clone());
+        let concern = get_concern();
+
+        for &now in &[50, 100] {
+            clock.set(now);
+            match abort_by_deadline_or_idle(&node, &concern, 7.into(), 100)
+                .unwrap()
+            {
+                Reaction::Idle => {}
+                _ => panic!("aborted at {} before the deadline", now),
+            }
+        }
+    }
+
+    #[test]
+    fn aborts_once_the_deadline_has_passed() {
+        let clock = Arc::new(ManualClock::new(100));
+        let node = get_node(clock.clone());
+        let concern = get_concern();
+
+        clock.advance(1);
+        match abort_by_deadline_or_idle(&node, &concern, 7.into(), 100)
+            .unwrap()
+        {
+            Reaction::Transaction(request) => {
+                assert_eq!(request.function, "abortByDeadline");
+                assert_eq!(request.data, vec![Token::Uint(7.into())]);
+            }
+            _ => panic!("not aborted after the deadline"),
+        }
+    }
+
+    fn get_drive() -> Drive {
+        Drive {
+            position: DRIVE_POSITION.into(),
+            log2_size: LOG2_SIZE.into(),
+            direct_value: b"input".to_vec(),
+            ipfs_path: String::new(),
+            root_hash: H256::zero(),
+            provider: Address::zero(),
+            waits_provider: false,
+            needs_logger: false,
+            download_as_car: false,
+        }
+    }
+
+    fn get_output() -> Vec<u8> {
+        let mut output = b"output".to_vec();
+        output.resize(1 << LOG2_SIZE, 0);
+        output
+    }
+
+    /// Node whose results go to a directory of its own
+    fn get_machine_node(name: &str) -> Node {
+        let mut config = ComputeConfig::default();
+        config.storage.results_path = std::env::temp_dir()
+            .join(format!("cartesi-compute-{}-{}", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        std::fs::create_dir_all(&config.storage.results_path).unwrap();
+        config.main_concern = Some(ConcernConfig {
+            abi: get_deployment_abi("goerli"),
+        });
+        Node::new(
+            config,
+            Arc::new(ManualClock::new(0)),
+            Arc::new(FixedGasEstimator::new(GAS_ESTIMATE)),
+        )
+    }
+
+    /// Initial and final hashes of the machine of the instance
+    fn get_machine_hashes(emulator: &MockEmulator) -> (H256, H256) {
+        let template_hash = MockEmulator::get_template_hash();
+        emulator.new_session("hashes", template_hash).unwrap();
+        emulator
+            .write("hashes", 0, DRIVE_POSITION, LOG2_SIZE, b"input".to_vec())
+            .unwrap();
+        let hashes = emulator.run("hashes", vec![0, FINAL_TIME]).unwrap();
+        emulator.end_session("hashes").unwrap();
+        (hashes[0], hashes[1])
+    }
+
+    fn react(
+        node: &Node,
+        emulator: &dyn EmulatorBackend,
+        role: Role,
+        initial_hash: H256,
+        claimed_final_hash: H256,
+        claimed_output: Vec<u8>,
+    ) -> Result<Reaction> {
+        react_by_machine_output(
+            node,
+            emulator,
+            &|_: &Drive| Err("No drive images in tests".into()),
+            &get_concern(),
+            7.into(),
+            &role,
+            vec![get_drive()],
+            MockEmulator::get_template_hash(),
+            initial_hash,
+            claimed_final_hash,
+            claimed_output,
+            FINAL_TIME.into(),
+            OUTPUT_POSITION.into(),
+            LOG2_SIZE.into(),
+            build_machine_id(7.into(), &get_concern().user_address),
+        )
+    }
+
+    #[test]
+    fn parses_the_deployed_get_state() {
+        let networks =
+            ["avax_testnet", "bsc_testnet", "goerli", "matic_testnet"];
+        for network in &networks {
+            let abi_path = get_deployment_abi(network);
+            CartesiComputeCtxParsed::check_abi(&abi_path).unwrap();
+            // so the node reveals drives with revealLoggerDrive
+            assert!(!abi::has_function(
+                &abi_path,
+                "revealLoggerDriveWithIpfsPath"
+            ));
+        }
+    }
+
+    fn get_function(reaction: Reaction) -> String {
+        match reaction {
+            Reaction::Transaction(request) => request.function,
+            _ => panic!("no transaction"),
+        }
+    }
+
+    #[test]
+    fn claimer_submits_the_machine_output() {
+        let node = get_machine_node("claimer");
+        let emulator =
+            MockEmulator::new().with_output(OUTPUT_POSITION, get_output());
+        let (initial_hash, final_hash) = get_machine_hashes(&emulator);
+
+        let reaction = react(
+            &node,
+            &emulator,
+            Role::Claimer,
+            initial_hash,
+            H256::zero(),
+            vec![],
+        )
+        .unwrap();
+        match reaction {
+            Reaction::Transaction(request) => {
+                assert_eq!(request.function, "submitClaim");
+                assert_eq!(
+                    request.data[1],
+                    Token::FixedBytes(final_hash.to_fixed_bytes().to_vec())
+                );
+                assert_eq!(request.data[3], Token::Bytes(get_output()));
+                // the default multiplier of 1.5 over the estimate
+                assert_eq!(request.gas, Some(U256::from(150_000)));
+            }
+            _ => panic!("no claim submitted"),
+        }
+    }
+
+    #[test]
+    fn voter_confirms_a_matching_claim() {
+        let node = get_machine_node("confirm");
+        let emulator =
+            MockEmulator::new().with_output(OUTPUT_POSITION, get_output());
+        let (initial_hash, final_hash) = get_machine_hashes(&emulator);
+
+        let reaction = react(
+            &node,
+            &emulator,
+            Role::Voter,
+            initial_hash,
+            final_hash,
+            get_output(),
+        )
+        .unwrap();
+        assert_eq!(get_function(reaction), "confirm");
+    }
+
+    #[test]
+    fn voter_challenges_a_mismatching_claim() {
+        let node = get_machine_node("challenge");
+        let emulator =
+            MockEmulator::new().with_output(OUTPUT_POSITION, get_output());
+        let (initial_hash, _) = get_machine_hashes(&emulator);
+
+        let reaction = react(
+            &node,
+            &emulator,
+            Role::Voter,
+            initial_hash,
+            H256::repeat_byte(1),
+            get_output(),
+        )
+        .unwrap();
+        assert_eq!(get_function(reaction), "challenge");
+    }
+
+    #[test]
+    fn rebuilds_a_session_the_emulator_lost() {
+        let node = get_machine_node("lost");
+        let emulator =
+            MockEmulator::new().with_output(OUTPUT_POSITION, get_output());
+        let (initial_hash, _) = get_machine_hashes(&emulator);
+        let machine_id =
+            build_machine_id(7.into(), &get_concern().user_address);
+
+        for _ in 0..2 {
+            react(
+                &node,
+                &emulator,
+                Role::Claimer,
+                initial_hash,
+                H256::zero(),
+                vec![],
+            )
+            .unwrap();
+            assert_eq!(emulator.get_session_ids(), vec![machine_id.clone()]);
+        }
+
+        emulator.end_session(&machine_id).unwrap();
+        let reaction = react(
+            &node,
+            &emulator,
+            Role::Claimer,
+            initial_hash,
+            H256::zero(),
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(get_function(reaction), "submitClaim");
+        assert_eq!(
+            emulator.get_session_ids(),
+            vec![format!("{}:1", machine_id)]
+        );
+    }
+
+    /// Emulator reached through an archive, which only answers a request
+    /// on the reaction after the one first sending it
+    struct ArchivedEmulator {
+        emulator: MockEmulator,
+        sent: Mutex<HashSet<String>>,
+    }
+
+    impl ArchivedEmulator {
+        fn answer<T>(
+            &self,
+            key: String,
+            respond: &dyn Fn() -> Result<T>,
+        ) -> Result<T> {
+            if self.sent.lock().unwrap().insert(key.clone()) {
+                return Err(Error::from(ErrorKind::ServiceNeedsRetry(
+                    "emulator".to_string(),
+                    key,
+                    String::new(),
+                    vec![],
+                    "CartesiCompute".to_string(),
+                    1,
+                    0,
+                    "Request sent".to_string(),
+                )));
+            }
+            respond()
+        }
+    }
+
+    impl EmulatorBackend for ArchivedEmulator {
+        fn new_session(
+            &self,
+            session_id: &str,
+            template_hash: H256,
+        ) -> Result<()> {
+            self.answer(format!("new {}", session_id), &|| {
+                self.emulator.new_session(session_id, template_hash)
+            })
+        }
+
+        fn end_session(&self, session_id: &str) -> Result<()> {
+            self.answer(format!("end {}", session_id), &|| {
+                self.emulator.end_session(session_id)
+            })
+        }
+
+        fn write(
+            &self,
+            session_id: &str,
+            time: u64,
+            address: u64,
+            log2_size: u64,
+            data: Vec<u8>,
+        ) -> Result<()> {
+            let key = format!("write {} {} {:x}", session_id, time, address);
+            self.answer(key, &|| {
+                self.emulator.write(
+                    session_id,
+                    time,
+                    address,
+                    log2_size,
+                    data.clone(),
+                )
+            })
+        }
+
+        fn replace(
+            &self,
+            session_id: &str,
+            time: u64,
+            address: u64,
+            log2_size: u64,
+            image_path: &str,
+        ) -> Result<()> {
+            let key = format!("replace {} {} {:x}", session_id, time, address);
+            self.answer(key, &|| {
+                self.emulator
+                    .replace(session_id, time, address, log2_size, image_path)
+            })
+        }
+
+        fn run(&self, session_id: &str, times: Vec<u64>) -> Result<Vec<H256>> {
+            self.answer(format!("run {} {:?}", session_id, times), &|| {
+                self.emulator.run(session_id, times.clone())
+            })
+        }
+
+        fn read(
+            &self,
+            session_id: &str,
+            time: u64,
+            address: u64,
+            length: u64,
+        ) -> Result<Vec<u8>> {
+            let key = format!("read {} {} {:x}", session_id, time, address);
+            self.answer(key, &|| {
+                self.emulator.read(session_id, time, address, length)
+            })
+        }
+
+        fn proof(
+            &self,
+            session_id: &str,
+            time: u64,
+            address: u64,
+            log2_size: u64,
+        ) -> Result<Proof> {
+            let key = format!("proof {} {} {:x}", session_id, time, address);
+            self.answer(key, &|| {
+                self.emulator.proof(session_id, time, address, log2_size)
+            })
+        }
+    }
+
+    #[test]
+    fn reuses_a_live_session_without_a_retry() {
+        let node = get_machine_node("live");
+        let emulator = ArchivedEmulator {
+            emulator: MockEmulator::new()
+                .with_output(OUTPUT_POSITION, get_output()),
+            sent: Mutex::new(HashSet::new()),
+        };
+        let (initial_hash, _) = get_machine_hashes(&emulator.emulator);
+        let react_as_claimer = || {
+            react(
+                &node,
+                &emulator,
+                Role::Claimer,
+                initial_hash,
+                H256::zero(),
+                vec![],
+            )
+        };
+
+        // building the session takes a reaction for each request
+        let mut retries = 0;
+        while let Err(e) = react_as_claimer() {
+            match e.kind() {
+                ErrorKind::ServiceNeedsRetry(..) => retries += 1,
+                _ => panic!("reaction failed: {}", e),
+            }
+            assert!(retries < 16);
+        }
+        assert!(retries > 0);
+
+        let reaction = react_as_claimer().unwrap();
+        assert_eq!(get_function(reaction), "submitClaim");
+    }
+
+    #[test]
+    fn refuses_a_machine_not_matching_the_initial_hash() {
+        let node = get_machine_node("mismatch");
+        let emulator =
+            MockEmulator::new().with_output(OUTPUT_POSITION, get_output());
+        let (_, final_hash) = get_machine_hashes(&emulator);
+
+        for role in vec![Role::Claimer, Role::Voter] {
+            assert!(react(
+                &node,
+                &emulator,
+                role,
+                H256::repeat_byte(1),
+                final_hash,
+                get_output(),
+            )
+            .is_err());
+        }
+    }
+
+    /// Services of a node that only reacts on the contract state
+    struct NoServices;
+
+    impl ServiceClient for NoServices {
+        fn get_response(
+            &self,
+            service: String,
+            key: String,
+            _method: String,
+            _request: Vec<u8>,
+        ) -> Result<Vec<u8>> {
+            Err(format!("Unexpected {} request {}", service, key).into())
+        }
+
+        fn get_run_result(
+            &self,
+            key: String,
+            _request: Vec<u8>,
+        ) -> Result<SessionRunResult> {
+            Err(format!("Unexpected run request {}", key).into())
+        }
+
+        fn as_archive(&self) -> Option<&Archive> {
+            None
+        }
+    }
+
+    fn get_party(is_party: bool, has_voted: bool, array_idx: u64) -> Party {
+        PartyParsed {
+            is_party: is_party,
+            has_voted: has_voted,
+            has_cheated: false,
+            array_idx: array_idx.into(),
+        }
+        .into()
+    }
+
+    /// Instance in `state` past its deadline of 100, claimed by address
+    /// 3 and seen by the party `party` of our address
+    fn get_ctx(
+        state: State,
+        challenger: Address,
+        party: Party,
+    ) -> CartesiComputeCtx {
+        CartesiComputeCtx {
+            template_hash: MockEmulator::get_template_hash(),
+            initial_hash: H256::zero(),
+            claimed_final_hash: H256::zero(),
+            claimed_output: vec![],
+            claimer: Address::from_low_u64_be(3),
+            challenger: challenger,
+            deadline: 100.into(),
+            output_position: OUTPUT_POSITION.into(),
+            output_log2_size: LOG2_SIZE.into(),
+            final_time: FINAL_TIME.into(),
+            current_state: state,
+            input_drives: vec![get_drive()],
+            partyState: party,
+            noChallengeDrive: false,
+        }
+    }
+
+    #[test]
+    fn recognises_the_challenger_at_index_0() {
+        let user_address = get_concern().user_address;
+        let ctx = get_ctx(
+            State::WaitingChallengeResult,
+            Address::zero(),
+            get_party(true, true, 0),
+        );
+        assert_eq!(get_role(user_address, &ctx), Role::Challenger);
+
+        // any other party that voted waits for the challenge result
+        let ctx = get_ctx(
+            State::WaitingChallengeResult,
+            Address::zero(),
+            get_party(true, true, 1),
+        );
+        assert_eq!(get_role(user_address, &ctx), Role::Voted);
+
+        let ctx = get_ctx(
+            State::WaitingChallengeResult,
+            user_address,
+            get_party(true, true, 1),
+        );
+        assert_eq!(get_role(user_address, &ctx), Role::Challenger);
+
+        // the zero address only names a challenger during a challenge
+        let ctx = get_ctx(
+            State::WaitingConfirmationDeadline,
+            Address::zero(),
+            get_party(true, true, 0),
+        );
+        assert_eq!(get_role(user_address, &ctx), Role::Voted);
+    }
+
+    #[test]
+    fn only_voters_abort_a_late_claimer() {
+        let node = get_node(Arc::new(ManualClock::new(101)));
+        let no_drives = |_: &Drive, _: bool| -> Result<String> {
+            Err("No drive images in tests".into())
+        };
+
+        let parties = vec![
+            (get_party(false, false, 0), Role::Observer),
+            (get_party(true, true, 1), Role::Voted),
+        ];
+        for (party, role) in parties {
+            let ctx = get_ctx(State::WaitingClaim, Address::zero(), party);
+            assert_eq!(get_role(get_concern().user_address, &ctx), role);
+
+            let reaction = CartesiCompute::react_to_instance(
+                &node,
+                &NoServices,
+                &MockEmulator::new(),
+                &no_drives,
+                &get_concern(),
+                7.into(),
+                ctx,
+                None,
+            )
+            .unwrap();
+            match reaction {
+                Reaction::Idle => {}
+                _ => panic!("{:?} reacted to a late claimer", role),
+            }
+        }
+    }
+}
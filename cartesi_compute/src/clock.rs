@@ -0,0 +1,179 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! The time deadlines are decided against.
+//!
+//! The contract checks deadlines against `block.timestamp`, so the node
+//! takes the timestamp of the latest confirmed block as the current time,
+//! and a skewed host clock can't make it act too early or miss its turn.
+//! Deadline decisions are all about a deadline having passed, which the
+//! confirmed block only ever tells late, never early. The local time is
+//! used instead only when configured, while the chain can't be reached.
+
+use super::error::*;
+use super::web3::futures::Future;
+use super::web3::transports::{EventLoopHandle, Http};
+use super::web3::types::{BlockId, BlockNumber};
+use super::web3::Web3;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    /// Seconds since the epoch
+    fn now(&self) -> Result<u64>;
+}
+
+impl<T: Clock> Clock for Arc<T> {
+    fn now(&self) -> Result<u64> {
+        (**self).now()
+    }
+}
+
+/// Time of the host
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    fn now(&self) -> Result<u64> {
+        Ok(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .chain_err(|| "System time before UNIX_EPOCH")?
+            .as_secs())
+    }
+}
+
+/// Timestamp of the latest block with `confirmations` blocks on top
+pub struct BlockClock {
+    web3: Mutex<(EventLoopHandle, Web3<Http>)>,
+    confirmations: u64,
+    local_fallback: bool,
+}
+
+impl BlockClock {
+    pub fn new(
+        url: &str,
+        confirmations: u64,
+        local_fallback: bool,
+    ) -> Result<Self> {
+        let (event_loop, transport) = Http::new(url).map_err(|e| {
+            Error::from(format!("Could not connect to {}: {:?}", url, e))
+        })?;
+        Ok(BlockClock {
+            web3: Mutex::new((event_loop, Web3::new(transport))),
+            confirmations: confirmations,
+            local_fallback: local_fallback,
+        })
+    }
+
+    fn get_block_timestamp(&self) -> Result<u64> {
+        let web3 = &self.web3.lock().unwrap().1;
+        let latest = web3.eth().block_number().wait().map_err(|e| {
+            Error::from(format!("Could not get the latest block: {:?}", e))
+        })?;
+        let number = latest.as_u64().saturating_sub(self.confirmations);
+        let block = web3
+            .eth()
+            .block(BlockId::Number(BlockNumber::Number(number.into())))
+            .wait()
+            .map_err(|e| {
+                Error::from(format!("Could not get block {}: {:?}", number, e))
+            })?
+            .ok_or(Error::from(format!("Block {} not found", number)))?;
+        Ok(block.timestamp.as_u64())
+    }
+}
+
+impl Clock for BlockClock {
+    fn now(&self) -> Result<u64> {
+        match self.get_block_timestamp() {
+            Ok(timestamp) => Ok(timestamp),
+            Err(e) => {
+                if !self.local_fallback {
+                    return Err(e);
+                }
+                warn!("Using the local time: {}", e);
+                LocalClock.now()
+            }
+        }
+    }
+}
+
+/// Clock that only moves when told to, for tests
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        ManualClock {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.now.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Result<u64> {
+        Ok(self.now.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // nothing listens on the discard port
+    const UNREACHABLE_URL: &str = "http://127.0.0.1:9";
+
+    #[test]
+    fn block_clock_fails_without_the_chain() {
+        let clock = BlockClock::new(UNREACHABLE_URL, 0, false).unwrap();
+        assert!(clock.now().is_err());
+    }
+
+    #[test]
+    fn block_clock_falls_back_to_the_local_time() {
+        let clock = BlockClock::new(UNREACHABLE_URL, 0, true).unwrap();
+        let before = LocalClock.now().unwrap();
+        let now = clock.now().unwrap();
+        assert!(now >= before && now <= LocalClock.now().unwrap());
+    }
+
+    #[test]
+    fn manual_clock_only_moves_when_told() {
+        let clock = ManualClock::new(10);
+        clock.advance(5);
+        assert_eq!(clock.now().unwrap(), 15);
+        clock.set(3);
+        assert_eq!(clock.now().unwrap(), 3);
+    }
+}
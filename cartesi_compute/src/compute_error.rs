@@ -0,0 +1,79 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Typed errors for what the contract or a service hands over and can't
+//! be used as it is.
+//!
+//! The dispatcher only deals in the `error` crate, so a `ComputeError`
+//! becomes an `Error` of the matching kind once it leaves a conversion,
+//! keeping the typed error as its cause.
+
+use super::error::{Error, ErrorKind};
+use super::ethereum_types::U256;
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComputeError {
+    /// `getState` returned an unknown state or outputs of the wrong shape
+    InvalidState(String),
+    /// A drive of the instance can't be used the way the contract holds it
+    InvalidDrive { position: U256, reason: String },
+    /// A service answered with a response that can't be decoded
+    InvalidResponse { service: String, reason: String },
+}
+
+pub type ComputeResult<T> = std::result::Result<T, ComputeError>;
+
+impl fmt::Display for ComputeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ComputeError::InvalidState(reason) => {
+                write!(f, "Invalid contract state: {}", reason)
+            }
+            ComputeError::InvalidDrive { position, reason } => {
+                write!(f, "Drive at position {:x} {}", position, reason)
+            }
+            ComputeError::InvalidResponse { service, reason } => {
+                write!(f, "Invalid {} response: {}", service, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ComputeError {}
+
+impl From<ComputeError> for Error {
+    fn from(e: ComputeError) -> Self {
+        let kind = match e {
+            ComputeError::InvalidState(..)
+            | ComputeError::InvalidDrive { .. } => {
+                ErrorKind::InvalidContractState(e.to_string())
+            }
+            ComputeError::InvalidResponse { .. } => {
+                ErrorKind::Msg(e.to_string())
+            }
+        };
+        Error::with_chain(e, kind)
+    }
+}
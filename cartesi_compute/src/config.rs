@@ -0,0 +1,405 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! The parts of the dispatcher configuration file used by cartesi compute.
+//!
+//! The dispatcher parses the file itself; this module reads the same file
+//! again for the sections it does not know about. Once loaded, the
+//! configuration is handed to every reaction through `node::Node`.
+
+use super::error::*;
+use super::ethereum_types::{Address, H256};
+use super::serde_yaml;
+
+use std::path::Path;
+
+/// Environment variable the dispatcher reads the configuration path from
+const CONFIG_PATH_ENV: &'static str = "CARTESI_CONFIG_PATH";
+const CONFIG_PATH_ARG: &'static str = "--config_path";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConcernConfig {
+    pub abi: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ComputeConfig {
+    pub main_concern: Option<ConcernConfig>,
+    /// Ethereum node, shared with the dispatcher
+    pub url: Option<String>,
+    /// Blocks on top of one before the dispatcher trusts it
+    #[serde(default)]
+    pub confirmations: u64,
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default)]
+    pub clock: ClockConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub sessions: SessionsConfig,
+    #[serde(default)]
+    pub templates: TemplatesConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    #[serde(default)]
+    pub gas: GasConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+}
+
+impl ComputeConfig {
+    /// Load the configuration from the path given to the dispatcher
+    pub fn load() -> Result<ComputeConfig> {
+        ComputeConfig::from_file(&get_config_path()?)
+    }
+
+    pub fn from_file(path: &str) -> Result<ComputeConfig> {
+        let file = std::fs::File::open(path)
+            .chain_err(|| format!("Could not open config file {}", path))?;
+        serde_yaml::from_reader(file)
+            .chain_err(|| format!("Could not parse config file {}", path))
+    }
+
+    /// Check the configuration can be used by this node
+    pub fn validate(&self) -> Result<()> {
+        // getState is decoded as the abi declares it, so the node must
+        // not run without checking it
+        if self.main_concern.is_none() {
+            return Err(
+                "main_concern is required to check the contract abi".into()
+            );
+        }
+        self.storage
+            .validate()
+            .chain_err(|| "Invalid storage configuration")?;
+        if self.sessions.max_sessions == 0 {
+            return Err("At least one emulator session must be allowed".into());
+        }
+        if !(self.gas.multiplier >= 1.0) {
+            return Err(format!(
+                "Gas multiplier {} must be at least 1",
+                self.gas.multiplier
+            )
+            .into());
+        }
+        let schedule = &self.schedule;
+        if schedule.download_rate == 0
+            || schedule.cycles_per_second == 0
+            || schedule.max_concurrent_runs == 0
+        {
+            return Err("Schedule rates and runs must be positive".into());
+        }
+        if let Some(path) = &self.templates.registry_path {
+            if !Path::new(path).is_file() {
+                return Err(
+                    format!("Template registry {} not found", path).into()
+                );
+            }
+        }
+        Ok(())
+    }
+
+}
+
+/// Where templates and drives are stored, shared with the emulator,
+/// logger and ipfs services. When `network` is set, each root gets a
+/// subdirectory for it, so nodes of different networks can share a host.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Machine templates, one directory per template hash
+    pub templates_path: String,
+    /// Drive images exchanged with the logger and ipfs services
+    pub drives_path: String,
+    /// Content this node provides for its drives, one directory per
+    /// instance named as `build_instance_file_name` names it
+    pub inbox_path: String,
+    /// Results of the instances this node verified
+    pub results_path: String,
+    /// Transactions held back in dry-run and approval modes
+    pub outbox_path: String,
+    pub network: Option<String>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            templates_path: "/opt/cartesi/srv/compute/cartesi-machine".into(),
+            drives_path: "/opt/cartesi/srv/compute/flashdrive".into(),
+            inbox_path: "/opt/cartesi/srv/compute/drive_inbox".into(),
+            results_path: "/opt/cartesi/srv/compute/results".into(),
+            outbox_path: "/opt/cartesi/srv/compute/outbox".into(),
+            network: None,
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn templates_dir(&self) -> String {
+        self.network_dir(&self.templates_path)
+    }
+
+    pub fn drives_dir(&self) -> String {
+        self.network_dir(&self.drives_path)
+    }
+
+    pub fn inbox_dir(&self) -> String {
+        self.network_dir(&self.inbox_path)
+    }
+
+    pub fn results_dir(&self) -> String {
+        self.network_dir(&self.results_path)
+    }
+
+    pub fn outbox_dir(&self) -> String {
+        self.network_dir(&self.outbox_path)
+    }
+
+    /// Directory of the machine template with the given root hash
+    pub fn template_path(&self, template_hash: H256) -> String {
+        format!("{}/{:x}", self.templates_dir(), template_hash)
+    }
+
+    /// Drive image stored under `name`
+    pub fn drive_path(&self, name: &str) -> String {
+        format!("{}/{}", self.drives_dir(), name)
+    }
+
+    /// Drive image stored under `name`, relative to `drives_path`,
+    /// which is how the logger service refers to it
+    pub fn drive_name(&self, name: &str) -> String {
+        match &self.network {
+            Some(network) => format!("{}/{}", network, name),
+            None => name.to_string(),
+        }
+    }
+
+    fn network_dir(&self, root: &str) -> String {
+        match &self.network {
+            Some(network) => format!("{}/{}", root, network),
+            None => root.to_string(),
+        }
+    }
+
+    /// Paths are handed to other services, so they must be absolute
+    pub fn validate(&self) -> Result<()> {
+        if let Some(network) = &self.network {
+            if network.is_empty() || network.contains('/') {
+                return Err(
+                    format!("Invalid network name {:?}", network).into()
+                );
+            }
+        }
+
+        for path in &[
+            &self.templates_path,
+            &self.drives_path,
+            &self.inbox_path,
+            &self.results_path,
+            &self.outbox_path,
+        ] {
+            if !Path::new(path).is_absolute() {
+                return Err(format!("Path {} is not absolute", path).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the directories the node writes to. Templates are only
+    /// needed by this node when fetching them, so their directory is
+    /// created on demand.
+    pub fn create_dirs(&self) -> Result<()> {
+        let drives_dir = self.drives_dir();
+        std::fs::create_dir_all(&drives_dir).chain_err(|| {
+            format!("Could not create drives directory {}", drives_dir)
+        })?;
+        let metadata = std::fs::metadata(&drives_dir)?;
+        if metadata.permissions().readonly() {
+            return Err(
+                format!("Drives directory {} is read only", drives_dir).into()
+            );
+        }
+
+        let results_dir = self.results_dir();
+        std::fs::create_dir_all(&results_dir).chain_err(|| {
+            format!("Could not create results directory {}", results_dir)
+        })?;
+
+        let outbox_dir = self.outbox_dir();
+        std::fs::create_dir_all(&outbox_dir).chain_err(|| {
+            format!("Could not create outbox directory {}", outbox_dir)
+        })?;
+
+        let inbox_dir = self.inbox_dir();
+        if !Path::new(&inbox_dir).is_dir() {
+            warn!(
+                "Drive inbox {} not found, no drive will be provided",
+                inbox_dir
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Expectations the node plans its work around
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// Seconds the due work should be done before a deadline
+    pub safety_margin: u64,
+    /// Bytes per second drives are expected to download at
+    pub download_rate: u64,
+    /// Cycles per second the emulator is expected to run at
+    pub cycles_per_second: u64,
+    /// Machines running at the same time, nearest deadlines first
+    pub max_concurrent_runs: usize,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            safety_margin: 120,
+            download_rate: 1_000_000,
+            cycles_per_second: 50_000_000,
+            max_concurrent_runs: 4,
+        }
+    }
+}
+
+/// How the current time is taken for deadline decisions
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ClockConfig {
+    /// Use the local time while the latest confirmed block can't be read
+    pub local_fallback: bool,
+}
+
+/// Emulator sessions kept alive across reactions
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionsConfig {
+    /// Live sessions before the least recently used is ended
+    pub max_sessions: usize,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        SessionsConfig { max_sessions: 16 }
+    }
+}
+
+/// Where machine templates missing from storage are fetched from
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TemplatesConfig {
+    /// Yaml file mapping template hashes to their locations
+    pub registry_path: Option<String>,
+}
+
+/// Limits an instance must respect for this node to work on it, a
+/// watching node only keeps the cycle and drive limits; unset limits are
+/// not enforced
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// Cycles the machine may run for, bounding `finalTime`
+    pub max_cycles: Option<u64>,
+    /// Size in bytes of any single drive
+    pub max_drive_size: Option<u64>,
+    /// Size in bytes of all drives downloaded from the logger or ipfs
+    pub max_total_download: Option<u64>,
+    /// Templates the node accepts to run
+    pub allowed_templates: Option<Vec<H256>>,
+    /// Claimers, challengers and drive providers the node accepts to
+    /// work with
+    pub allowed_counterparties: Option<Vec<Address>>,
+}
+
+/// Gas limits of the transactions whose cost is estimated
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GasConfig {
+    /// Margin over the gas the ethereum node estimates, as a factor
+    pub multiplier: f64,
+    /// Estimates above this are rejected before sending, and limits with
+    /// the margin are capped to it
+    pub block_gas_limit: u64,
+}
+
+impl Default for GasConfig {
+    fn default() -> Self {
+        GasConfig {
+            multiplier: 1.5,
+            block_gas_limit: 12_500_000,
+        }
+    }
+}
+
+/// How the node takes part in the instances it sees
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Play the role of our address in every instance
+    Active,
+    /// Only recompute claims and record verdicts, never sending a
+    /// transaction; the template and counterparty limits of the policy
+    /// don't apply
+    Watch,
+    /// Play the role of our address, recording the transactions it
+    /// would send instead of sending them
+    DryRun,
+    /// Play the role of our address, sending each transaction once an
+    /// operator approves it
+    Approval,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Active
+    }
+}
+
+/// Path of the configuration file, taken from the command line the same
+/// way the dispatcher does, falling back to the environment
+fn get_config_path() -> Result<String> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == CONFIG_PATH_ARG {
+            return args.next().ok_or(Error::from(format!(
+                "Missing value for {}",
+                CONFIG_PATH_ARG
+            )));
+        }
+        let prefix = format!("{}=", CONFIG_PATH_ARG);
+        if arg.starts_with(&prefix) {
+            return Ok(arg[prefix.len()..].to_string());
+        }
+    }
+    std::env::var(CONFIG_PATH_ENV).chain_err(|| {
+        format!(
+            "No configuration file given, with {} or {}",
+            CONFIG_PATH_ARG, CONFIG_PATH_ENV
+        )
+    })
+}
@@ -0,0 +1,739 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! The emulator operations cartesi compute relies on, behind a trait.
+//!
+//! `ArchiveEmulator` talks to the machine manager through the dispatcher
+//! archive, which is what the node runs with, or through any other
+//! `ServiceClient`. `MockEmulator` keeps a
+//! sparse machine in memory, hashed the way the machine manager hashes
+//! it, so the reactions can be driven without any service.
+
+use super::compute::{
+    build_session_end_key, build_session_proof_key, build_session_read_key,
+    build_session_replace_key, build_session_run_key, build_session_write_key,
+    cartesi_machine, get_run_result, EndSessionRequest, NewSessionRequest,
+    NewSessionResponse, SessionGetProofRequest, SessionGetProofResponse,
+    SessionReadMemoryRequest, SessionReadMemoryResponse,
+    SessionReplaceMemoryRangeRequest, SessionRunRequest, SessionRunResult,
+    SessionWriteMemoryRequest, EMULATOR_METHOD_END, EMULATOR_METHOD_NEW,
+    EMULATOR_METHOD_PROOF, EMULATOR_METHOD_READ, EMULATOR_METHOD_REPLACE,
+    EMULATOR_METHOD_WRITE, EMULATOR_SERVICE_NAME,
+};
+use super::dispatcher::Archive;
+use super::error::*;
+use super::ethereum_types::H256;
+use super::node::Node;
+use super::{merkle, templates};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Log2 size of the machine address space
+pub const MACHINE_LOG2_SIZE: u64 = 64;
+
+/// Proof of a range of the machine memory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proof {
+    /// Hash of the range
+    pub target_hash: H256,
+    /// Siblings in the order the emulator lists them, from the root
+    /// down; see `proof` for the order the contract expects
+    pub sibling_hashes: Vec<H256>,
+    /// Machine hash
+    pub root_hash: H256,
+}
+
+/// Emulator sessions, each a machine built from a template that can be
+/// modified at time 0 and inspected at any time
+pub trait EmulatorBackend {
+    /// Create a session from the template with the given root hash,
+    /// replacing any session with the same id
+    fn new_session(&self, session_id: &str, template_hash: H256)
+        -> Result<()>;
+
+    /// End the session. Requests on a session the emulator does not
+    /// hold, once ended or lost, fail with `ResponseInvalidError`.
+    fn end_session(&self, session_id: &str) -> Result<()>;
+
+    /// Fill the range of `2^log2_size` bytes at `address` with `data`,
+    /// zero padded
+    fn write(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        log2_size: u64,
+        data: Vec<u8>,
+    ) -> Result<()>;
+
+    /// Back the range of `2^log2_size` bytes at `address` with the image
+    /// stored at `image_path`
+    fn replace(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        log2_size: u64,
+        image_path: &str,
+    ) -> Result<()>;
+
+    /// Machine hash at each of `times`
+    fn run(&self, session_id: &str, times: Vec<u64>) -> Result<Vec<H256>>;
+
+    fn read(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        length: u64,
+    ) -> Result<Vec<u8>>;
+
+    fn proof(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        log2_size: u64,
+    ) -> Result<Proof>;
+}
+
+/// Requests to the services of the node, answered the way the
+/// dispatcher archive answers them
+pub trait ServiceClient {
+    fn get_response(
+        &self,
+        service: String,
+        key: String,
+        method: String,
+        request: Vec<u8>,
+    ) -> Result<Vec<u8>>;
+
+    /// Result of a run request, once the emulator is done running
+    fn get_run_result(
+        &self,
+        key: String,
+        request: Vec<u8>,
+    ) -> Result<SessionRunResult>;
+
+    /// The dispatcher archive itself, which the verification game needs;
+    /// `None` for the clients standing in for it
+    fn as_archive(&self) -> Option<&Archive>;
+}
+
+impl ServiceClient for Archive {
+    fn get_response(
+        &self,
+        service: String,
+        key: String,
+        method: String,
+        request: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        Archive::get_response(self, service, key, method, request)
+    }
+
+    fn get_run_result(
+        &self,
+        key: String,
+        request: Vec<u8>,
+    ) -> Result<SessionRunResult> {
+        get_run_result(self, "CartesiCompute".to_string(), key, request)
+    }
+
+    fn as_archive(&self) -> Option<&Archive> {
+        Some(self)
+    }
+}
+
+/// The machine manager, reached through the dispatcher archive. Each
+/// request is only sent once and answered from the archive afterwards.
+/// Templates are resolved as the configuration of `node` says.
+pub struct ArchiveEmulator<'a> {
+    archive: &'a dyn ServiceClient,
+    node: &'a Node,
+}
+
+impl<'a> ArchiveEmulator<'a> {
+    pub fn new(archive: &'a dyn ServiceClient, node: &'a Node) -> Self {
+        ArchiveEmulator {
+            archive: archive,
+            node: node,
+        }
+    }
+}
+
+/// Root hash of the stored machine in `directory`, loaded by the machine
+/// manager in a session of its own that is ended right after
+pub fn get_stored_machine_hash(
+    archive: &dyn ServiceClient,
+    session_id: &str,
+    directory: &str,
+) -> Result<H256> {
+    start_session(archive, session_id, directory)?;
+    let request = SessionRunRequest {
+        session_id: session_id.to_string(),
+        times: vec![0],
+    };
+    let archive_key = build_session_run_key(session_id.to_string(), vec![0]);
+    let hashes = archive.get_run_result(archive_key, request.into())?.hashes;
+    if let Err(e) = end_session(archive, session_id) {
+        warn!("Could not end emulator session {}: {}", session_id, e);
+    }
+    hashes.first().cloned().ok_or(Error::from(format!(
+        "Emulator returned no hash for the machine in {}",
+        directory
+    )))
+}
+
+fn start_session(
+    archive: &dyn ServiceClient,
+    session_id: &str,
+    directory: &str,
+) -> Result<()> {
+    let mut machine = cartesi_machine::MachineRequest::new();
+    machine.set_directory(directory.to_string());
+
+    // replace any session left behind by a previous run of the node
+    let request = NewSessionRequest {
+        session_id: session_id.to_string(),
+        machine: machine,
+        force: true,
+    };
+
+    let _processed_response: NewSessionResponse = archive
+        .get_response(
+            EMULATOR_SERVICE_NAME.to_string(),
+            session_id.to_string(),
+            EMULATOR_METHOD_NEW.to_string(),
+            request.into(),
+        )?
+        .into();
+    Ok(())
+}
+
+fn end_session(archive: &dyn ServiceClient, session_id: &str) -> Result<()> {
+    let request = EndSessionRequest {
+        session_id: session_id.to_string(),
+        silent: true,
+    };
+
+    let _processed_response = archive.get_response(
+        EMULATOR_SERVICE_NAME.to_string(),
+        build_session_end_key(session_id.to_string()),
+        EMULATOR_METHOD_END.to_string(),
+        request.into(),
+    )?;
+    Ok(())
+}
+
+impl<'a> EmulatorBackend for ArchiveEmulator<'a> {
+    fn new_session(
+        &self,
+        session_id: &str,
+        template_hash: H256,
+    ) -> Result<()> {
+        let template_path =
+            templates::resolve(self.archive, self.node, template_hash)?;
+        start_session(self.archive, session_id, &template_path)
+    }
+
+    fn end_session(&self, session_id: &str) -> Result<()> {
+        end_session(self.archive, session_id)
+    }
+
+    fn write(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        log2_size: u64,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let archive_key = build_session_write_key(
+            session_id.to_string(),
+            time,
+            address,
+            data.clone(),
+        );
+
+        let mut padded_data = data;
+        padded_data.resize(1 << log2_size, 0);
+
+        let mut position = cartesi_machine::WriteMemoryRequest::new();
+        position.set_address(address);
+        position.set_data(padded_data);
+
+        let request = SessionWriteMemoryRequest {
+            session_id: session_id.to_string(),
+            time: time,
+            position: position,
+        };
+
+        let _processed_response = self.archive.get_response(
+            EMULATOR_SERVICE_NAME.to_string(),
+            archive_key,
+            EMULATOR_METHOD_WRITE.to_string(),
+            request.into(),
+        )?;
+        Ok(())
+    }
+
+    fn replace(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        log2_size: u64,
+        image_path: &str,
+    ) -> Result<()> {
+        let archive_key = build_session_replace_key(
+            session_id.to_string(),
+            time,
+            address,
+            image_path.to_string(),
+        );
+
+        let mut mrc = cartesi_machine::MemoryRangeConfig::new();
+        mrc.set_start(address);
+        mrc.set_length(1 << log2_size);
+        mrc.set_image_filename(image_path.to_string());
+        mrc.set_shared(false);
+
+        let request = SessionReplaceMemoryRangeRequest {
+            session_id: session_id.to_string(),
+            time: time,
+            range: mrc,
+        };
+
+        let _processed_response = self.archive.get_response(
+            EMULATOR_SERVICE_NAME.to_string(),
+            archive_key,
+            EMULATOR_METHOD_REPLACE.to_string(),
+            request.into(),
+        )?;
+        Ok(())
+    }
+
+    fn run(&self, session_id: &str, times: Vec<u64>) -> Result<Vec<H256>> {
+        let request = SessionRunRequest {
+            session_id: session_id.to_string(),
+            times: times.clone(),
+        };
+        let expected = times.len();
+        let archive_key = build_session_run_key(session_id.to_string(), times);
+
+        let processed_result =
+            self.archive.get_run_result(archive_key, request.into())?;
+        if processed_result.hashes.len() != expected {
+            return Err(format!(
+                "Emulator returned {} hashes for {} run times",
+                processed_result.hashes.len(),
+                expected
+            )
+            .into());
+        }
+        Ok(processed_result.hashes)
+    }
+
+    fn read(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        length: u64,
+    ) -> Result<Vec<u8>> {
+        let archive_key = build_session_read_key(
+            session_id.to_string(),
+            time,
+            address,
+            length,
+        );
+        let mut position = cartesi_machine::ReadMemoryRequest::new();
+        position.set_address(address);
+        position.set_length(length);
+
+        let request = SessionReadMemoryRequest {
+            session_id: session_id.to_string(),
+            time: time,
+            position: position,
+        };
+
+        let processed_response: SessionReadMemoryResponse = self
+            .archive
+            .get_response(
+                EMULATOR_SERVICE_NAME.to_string(),
+                archive_key,
+                EMULATOR_METHOD_READ.to_string(),
+                request.into(),
+            )?
+            .into();
+
+        trace!(
+            "Read memory result: {:?}...",
+            processed_response.read_content.data
+        );
+        Ok(processed_response.read_content.data)
+    }
+
+    fn proof(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        log2_size: u64,
+    ) -> Result<Proof> {
+        let archive_key = build_session_proof_key(
+            session_id.to_string(),
+            time,
+            address,
+            log2_size,
+        );
+        let mut target = cartesi_machine::GetProofRequest::new();
+        target.set_address(address);
+        target.set_log2_size(log2_size);
+
+        let request = SessionGetProofRequest {
+            session_id: session_id.to_string(),
+            time: time,
+            target: target,
+        };
+
+        let processed_response: SessionGetProofResponse = self
+            .archive
+            .get_response(
+                EMULATOR_SERVICE_NAME.to_string(),
+                archive_key,
+                EMULATOR_METHOD_PROOF.to_string(),
+                request.into(),
+            )?
+            .into();
+
+        trace!("Get proof result: {:?}...", processed_response.proof);
+        let proof = processed_response.proof;
+        Ok(Proof {
+            target_hash: proof.target_hash,
+            sibling_hashes: proof.sibling_hashes,
+            root_hash: proof.root_hash,
+        })
+    }
+}
+
+/// Deterministic emulator keeping its machines in memory. Every template
+/// is a zero filled machine, and running it for any number of cycles
+/// writes the configured outputs, so claims can be made right or wrong
+/// on purpose.
+#[derive(Default)]
+pub struct MockEmulator {
+    sessions: Mutex<HashMap<String, MockMachine>>,
+    /// Ranges written by running the machine, by address
+    outputs: BTreeMap<u64, Vec<u8>>,
+}
+
+/// Written ranges of a machine by address, each holding the data of a
+/// whole aligned range; the rest of the memory is zero
+#[derive(Default, Clone)]
+struct MockMachine {
+    ranges: BTreeMap<u64, Vec<u8>>,
+}
+
+impl MockEmulator {
+    pub fn new() -> Self {
+        MockEmulator::default()
+    }
+
+    /// Have the machine write `data` at `address` when run
+    pub fn with_output(mut self, address: u64, data: Vec<u8>) -> Self {
+        self.outputs.insert(address, data);
+        self
+    }
+
+    /// Root hash of the templates of this emulator
+    pub fn get_template_hash() -> H256 {
+        MockMachine::default().get_root_hash()
+    }
+
+    /// Ids of the live sessions
+    pub fn get_session_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> =
+            self.sessions.lock().unwrap().keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    fn get_machine(&self, session_id: &str, time: u64) -> Result<MockMachine> {
+        let mut machine = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .cloned()
+            .ok_or(get_no_session_error(session_id))?;
+        if time > 0 {
+            for (address, data) in &self.outputs {
+                machine.write(*address, data.clone());
+            }
+        }
+        Ok(machine)
+    }
+
+    fn modify(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        log2_size: u64,
+        mut data: Vec<u8>,
+    ) -> Result<()> {
+        if time != 0 {
+            return Err("Mock machines can only be modified at time 0".into());
+        }
+        if log2_size < merkle::WORD_LOG2_SIZE
+            || log2_size >= MACHINE_LOG2_SIZE
+            || address % (1 << log2_size) != 0
+            || data.len() as u64 > 1 << log2_size
+        {
+            return Err(format!(
+                "Invalid range of {} bytes at {:x} with log2 size {}",
+                data.len(),
+                address,
+                log2_size
+            )
+            .into());
+        }
+        data.resize(1 << log2_size, 0);
+        self.sessions
+            .lock()
+            .unwrap()
+            .get_mut(session_id)
+            .ok_or(get_no_session_error(session_id))?
+            .write(address, data);
+        Ok(())
+    }
+}
+
+/// Error the machine manager answers requests on a missing session with
+fn get_no_session_error(session_id: &str) -> Error {
+    Error::from(ErrorKind::ResponseInvalidError(
+        EMULATOR_SERVICE_NAME.to_string(),
+        session_id.to_string(),
+        String::new(),
+    ))
+}
+
+impl EmulatorBackend for MockEmulator {
+    fn new_session(
+        &self,
+        session_id: &str,
+        _template_hash: H256,
+    ) -> Result<()> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session_id.to_string(), MockMachine::default());
+        Ok(())
+    }
+
+    fn end_session(&self, session_id: &str) -> Result<()> {
+        self.sessions.lock().unwrap().remove(session_id);
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        log2_size: u64,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        self.modify(session_id, time, address, log2_size, data)
+    }
+
+    fn replace(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        log2_size: u64,
+        image_path: &str,
+    ) -> Result<()> {
+        let data = std::fs::read(image_path)
+            .chain_err(|| format!("Could not read image {}", image_path))?;
+        self.modify(session_id, time, address, log2_size, data)
+    }
+
+    fn run(&self, session_id: &str, times: Vec<u64>) -> Result<Vec<H256>> {
+        times
+            .into_iter()
+            .map(|time| Ok(self.get_machine(session_id, time)?.get_root_hash()))
+            .collect()
+    }
+
+    fn read(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        length: u64,
+    ) -> Result<Vec<u8>> {
+        Ok(self.get_machine(session_id, time)?.read(address, length))
+    }
+
+    fn proof(
+        &self,
+        session_id: &str,
+        time: u64,
+        address: u64,
+        log2_size: u64,
+    ) -> Result<Proof> {
+        if log2_size < merkle::WORD_LOG2_SIZE
+            || log2_size > MACHINE_LOG2_SIZE
+            || (log2_size < MACHINE_LOG2_SIZE
+                && address % (1 << log2_size) != 0)
+        {
+            return Err(format!(
+                "Invalid proof target {:x} with log2 size {}",
+                address, log2_size
+            )
+            .into());
+        }
+        let machine = self.get_machine(session_id, time)?;
+        let sibling_hashes = (log2_size..MACHINE_LOG2_SIZE)
+            .rev()
+            .map(|level| {
+                let sibling = ((address as u128 >> level) ^ 1) << level;
+                machine.get_node_hash(sibling, level)
+            })
+            .collect::<Result<Vec<H256>>>()?;
+        Ok(Proof {
+            target_hash: machine.get_node_hash(address as u128, log2_size)?,
+            sibling_hashes: sibling_hashes,
+            root_hash: machine.get_root_hash(),
+        })
+    }
+}
+
+impl MockMachine {
+    /// Write an aligned range, dropping the ranges it overlaps
+    fn write(&mut self, address: u64, data: Vec<u8>) {
+        let end = address as u128 + data.len() as u128;
+        let overlapping: Vec<u64> = self
+            .ranges
+            .iter()
+            .filter(|(start, range)| {
+                **start as u128 + (range.len() as u128) > address as u128
+                    && (**start as u128) < end
+            })
+            .map(|(start, _)| *start)
+            .collect();
+        for start in overlapping {
+            let range = self.ranges.remove(&start).unwrap();
+            // keep what the write leaves untouched
+            let range_end = start as u128 + range.len() as u128;
+            if start < address {
+                let kept = (address - start) as usize;
+                self.ranges.insert(start, range[..kept].to_vec());
+            }
+            if range_end > end {
+                let offset = (end - start as u128) as usize;
+                self.ranges.insert(end as u64, range[offset..].to_vec());
+            }
+        }
+        self.ranges.insert(address, data);
+    }
+
+    fn read(&self, address: u64, length: u64) -> Vec<u8> {
+        let mut data = vec![0u8; length as usize];
+        let end = address as u128 + length as u128;
+        for (start, range) in &self.ranges {
+            let start = *start as u128;
+            let range_end = start + range.len() as u128;
+            if range_end <= address as u128 || start >= end {
+                continue;
+            }
+            let from = std::cmp::max(start, address as u128);
+            let to = std::cmp::min(range_end, end);
+            data[(from - address as u128) as usize
+                ..(to - address as u128) as usize]
+                .copy_from_slice(
+                    &range[(from - start) as usize..(to - start) as usize],
+                );
+        }
+        data
+    }
+
+    fn get_root_hash(&self) -> H256 {
+        // the whole machine can always be hashed
+        self.get_node_hash(0, MACHINE_LOG2_SIZE).unwrap()
+    }
+
+    /// Hash of the aligned node of `2^log2_size` bytes at `address`
+    fn get_node_hash(&self, address: u128, log2_size: u64) -> Result<H256> {
+        let end = address + (1u128 << log2_size);
+        let mut overlapping = self.ranges.iter().filter(|(start, range)| {
+            let start = **start as u128;
+            start + (range.len() as u128) > address && start < end
+        });
+        let first = overlapping.next();
+        if first.is_none() {
+            return match log2_size {
+                MACHINE_LOG2_SIZE => {
+                    let half = merkle::get_pristine_hash(log2_size - 1)?;
+                    Ok(merkle::hash_pair(&half, &half))
+                }
+                _ => merkle::get_pristine_hash(log2_size),
+            };
+        }
+
+        // a node within a single range hashes from its data
+        let (start, range) = first.unwrap();
+        let start = *start as u128;
+        if overlapping.next().is_none()
+            && start <= address
+            && start + range.len() as u128 >= end
+            && log2_size < MACHINE_LOG2_SIZE
+        {
+            let offset = (address - start) as usize;
+            return merkle::get_root_hash(
+                &range[offset..offset + (1 << log2_size)],
+                merkle::WORD_LOG2_SIZE,
+                log2_size,
+            );
+        }
+
+        // ranges split by other writes may not cover whole words
+        if log2_size == merkle::WORD_LOG2_SIZE {
+            return merkle::get_root_hash(
+                &self.read(address as u64, 1 << log2_size),
+                merkle::WORD_LOG2_SIZE,
+                log2_size,
+            );
+        }
+
+        let half = 1u128 << (log2_size - 1);
+        Ok(merkle::hash_pair(
+            &self.get_node_hash(address, log2_size - 1)?,
+            &self.get_node_hash(address + half, log2_size - 1)?,
+        ))
+    }
+}
@@ -0,0 +1,382 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Gas limits of the transactions whose cost depends on their
+//! arguments.
+//!
+//! The ethereum node estimates the gas of the encoded call with
+//! `eth_estimateGas`, and the configured multiplier is the margin for
+//! the contract state changing before the transaction is mined. Limits
+//! that could never fit a block are rejected before sending.
+
+use super::abi;
+use super::config::GasConfig;
+use super::configuration::Concern;
+use super::error::*;
+use super::ethabi::Token;
+use super::ethereum_types::{Address, U256};
+use super::hex;
+use super::node::Node;
+use super::serde_json;
+use super::web3::futures::Future;
+use super::web3::transports::{EventLoopHandle, Http};
+use super::web3::{Transport, Web3};
+
+use std::sync::Mutex;
+
+/// Source of the gas estimates of contract calls
+pub trait GasEstimator: Send + Sync {
+    /// Gas a call from `from` to `to` with call data `data` uses
+    fn estimate(&self, from: Address, to: Address, data: &[u8])
+        -> Result<U256>;
+}
+
+/// Estimates of the ethereum node at a url
+pub struct Web3GasEstimator {
+    web3: Mutex<(EventLoopHandle, Web3<Http>)>,
+}
+
+impl Web3GasEstimator {
+    pub fn new(url: &str) -> Result<Self> {
+        let (event_loop, transport) = Http::new(url).map_err(|e| {
+            Error::from(format!("Could not connect to {}: {:?}", url, e))
+        })?;
+        Ok(Web3GasEstimator {
+            web3: Mutex::new((event_loop, Web3::new(transport))),
+        })
+    }
+}
+
+/// Call as `eth_estimateGas` takes it
+#[derive(Serialize)]
+struct EstimateGasCall {
+    from: Address,
+    to: Address,
+    data: String,
+}
+
+impl GasEstimator for Web3GasEstimator {
+    fn estimate(
+        &self,
+        from: Address,
+        to: Address,
+        data: &[u8],
+    ) -> Result<U256> {
+        let call = serde_json::to_value(EstimateGasCall {
+            from: from,
+            to: to,
+            data: format!("0x{}", hex::encode(data)),
+        })
+        .chain_err(|| "Could not serialize the call to estimate")?;
+        let web3 = &self.web3.lock().unwrap().1;
+        let estimate = web3
+            .transport()
+            .execute("eth_estimateGas", vec![call])
+            .wait()
+            .map_err(|e| {
+                Error::from(format!("Could not estimate gas: {:?}", e))
+            })?;
+        serde_json::from_value(estimate.clone())
+            .chain_err(|| format!("Invalid gas estimate {}", estimate))
+    }
+}
+
+/// Estimator of a node without an ethereum node to ask
+pub struct NoGasEstimator;
+
+impl GasEstimator for NoGasEstimator {
+    fn estimate(
+        &self,
+        _from: Address,
+        _to: Address,
+        _data: &[u8],
+    ) -> Result<U256> {
+        Err("No ethereum node configured to estimate gas".into())
+    }
+}
+
+/// Estimator answering every call with the same estimate and keeping
+/// the calls it was asked about, for tests
+pub struct FixedGasEstimator {
+    estimate: U256,
+    calls: Mutex<Vec<(Address, Address, Vec<u8>)>>,
+}
+
+impl FixedGasEstimator {
+    pub fn new(estimate: u64) -> Self {
+        FixedGasEstimator {
+            estimate: estimate.into(),
+            calls: Mutex::new(vec![]),
+        }
+    }
+
+    /// Sender, contract and call data of each call estimated so far
+    pub fn get_calls(&self) -> Vec<(Address, Address, Vec<u8>)> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl GasEstimator for FixedGasEstimator {
+    fn estimate(
+        &self,
+        from: Address,
+        to: Address,
+        data: &[u8],
+    ) -> Result<U256> {
+        self.calls.lock().unwrap().push((from, to, data.to_vec()));
+        Ok(self.estimate)
+    }
+}
+
+/// Gas limit for `submitClaim` with arguments `data`, sent by the user
+/// of `concern`
+pub fn estimate_submit_claim(
+    node: &Node,
+    concern: &Concern,
+    data: &[Token],
+) -> Result<U256> {
+    estimate_call(node, concern, "submitClaim", data)
+}
+
+/// Gas limit for calling `function` of the cartesi compute contract with
+/// arguments `data`, encoded as the contract abi declares it
+fn estimate_call(
+    node: &Node,
+    concern: &Concern,
+    function: &str,
+    data: &[Token],
+) -> Result<U256> {
+    let abi_path = match &node.config.main_concern {
+        Some(main_concern) => &main_concern.abi,
+        None => {
+            return Err(format!("No contract abi to encode {}", function).into())
+        }
+    };
+    let call_data = abi::encode_call(abi_path, function, data)?;
+    let estimate = node
+        .gas
+        .estimate(concern.user_address, concern.contract_address, &call_data)
+        .chain_err(|| format!("Could not estimate the gas of {}", function))?;
+    apply_gas_policy(&node.config.gas, function, estimate)
+}
+
+/// Scale `estimate` by the configured multiplier, up to the block gas
+/// limit, failing if the transaction could never fit a block
+fn apply_gas_policy(
+    gas: &GasConfig,
+    function: &str,
+    estimate: U256,
+) -> Result<U256> {
+    if estimate > U256::from(gas.block_gas_limit) {
+        return Err(format!(
+            "{} needs an estimated {} gas, over the block gas limit {}",
+            function, estimate, gas.block_gas_limit
+        )
+        .into());
+    }
+    let limit = (estimate.as_u64() as f64 * gas.multiplier).ceil() as u64;
+    let limit = std::cmp::min(limit, gas.block_gas_limit);
+    trace!("Estimated {} gas for {}, sending {}", estimate, function, limit);
+    Ok(U256::from(limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::clock::ManualClock;
+    use super::super::config::{ComputeConfig, ConcernConfig};
+    use super::super::ethabi;
+    use super::super::ethereum_types::H256;
+    use super::super::keccak_hash::keccak;
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    // nothing listens on the discard port
+    const UNREACHABLE_URL: &str = "http://127.0.0.1:9";
+
+    fn get_gas_config() -> GasConfig {
+        GasConfig {
+            multiplier: 1.5,
+            block_gas_limit: 1_000_000,
+        }
+    }
+
+    fn get_concern() -> Concern {
+        Concern {
+            contract_address: Address::from_low_u64_be(1),
+            user_address: Address::from_low_u64_be(2),
+        }
+    }
+
+    fn get_submit_claim_data() -> Vec<Token> {
+        let sibling = Token::FixedBytes(vec![1; 32]);
+        vec![
+            Token::Uint(7.into()),
+            Token::FixedBytes(H256::repeat_byte(2).to_fixed_bytes().to_vec()),
+            Token::Array(vec![Token::Array(vec![sibling.clone(); 3])]),
+            Token::Bytes(b"output".to_vec()),
+            Token::Array(vec![sibling; 2]),
+        ]
+    }
+
+    #[test]
+    fn applies_the_margin_up_to_the_block_gas_limit() {
+        let gas = get_gas_config();
+        let limit = |estimate: u64| {
+            apply_gas_policy(&gas, "submitClaim", estimate.into())
+        };
+
+        assert_eq!(limit(100_000).unwrap(), U256::from(150_000));
+        assert_eq!(limit(666_667).unwrap(), U256::from(1_000_000));
+        assert_eq!(limit(1_000_000).unwrap(), U256::from(1_000_000));
+        assert!(limit(1_000_001).is_err());
+    }
+
+    #[test]
+    fn estimates_the_encoded_submit_claim() {
+        let mut config = ComputeConfig::default();
+        config.gas = get_gas_config();
+        config.main_concern = Some(ConcernConfig {
+            abi: format!(
+                "{}/../deployments/goerli/CartesiCompute.json",
+                env!("CARGO_MANIFEST_DIR")
+            ),
+        });
+        let estimator = Arc::new(FixedGasEstimator::new(200_000));
+        let node =
+            Node::new(config, Arc::new(ManualClock::new(0)), estimator.clone());
+        let concern = get_concern();
+        let data = get_submit_claim_data();
+
+        let limit = estimate_submit_claim(&node, &concern, &data).unwrap();
+        assert_eq!(limit, U256::from(300_000));
+
+        let selector =
+            keccak("submitClaim(uint256,bytes32,bytes32[][],bytes,bytes32[])");
+        let mut call_data = selector.as_bytes()[..4].to_vec();
+        call_data.extend(ethabi::encode(&data));
+        assert_eq!(
+            estimator.get_calls(),
+            vec![(concern.user_address, concern.contract_address, call_data)]
+        );
+    }
+
+    #[test]
+    fn fails_to_estimate_without_an_abi_or_the_chain() {
+        let node = Node::new(
+            ComputeConfig::default(),
+            Arc::new(ManualClock::new(0)),
+            Arc::new(FixedGasEstimator::new(200_000)),
+        );
+        let data = get_submit_claim_data();
+        assert!(estimate_submit_claim(&node, &get_concern(), &data).is_err());
+
+        let estimator = Web3GasEstimator::new(UNREACHABLE_URL).unwrap();
+        assert!(estimator
+            .estimate(Address::zero(), Address::zero(), &[])
+            .is_err());
+    }
+
+    /// Answer a single json rpc request with `result`, returning the
+    /// request body
+    fn serve_once(
+        result: &'static str,
+    ) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            let body = loop {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).into_owned();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text[..end]
+                        .lines()
+                        .filter_map(|line| {
+                            let line = line.to_lowercase();
+                            if line.starts_with("content-length:") {
+                                line["content-length:".len()..]
+                                    .trim()
+                                    .parse::<usize>()
+                                    .ok()
+                            } else {
+                                None
+                            }
+                        })
+                        .next()
+                        .unwrap_or(0);
+                    if text.len() >= end + 4 + length {
+                        break text[end + 4..end + 4 + length].to_string();
+                    }
+                }
+            };
+
+            let id = serde_json::from_str::<serde_json::Value>(&body).unwrap()
+                ["id"]
+                .clone();
+            let response = format!(
+                r#"{{"jsonrpc":"2.0","id":{},"result":"{}"}}"#,
+                id, result
+            );
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            )
+            .unwrap();
+            body
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn asks_the_ethereum_node_for_the_estimate() {
+        let (url, server) = serve_once("0x5208");
+        let estimator = Web3GasEstimator::new(&url).unwrap();
+
+        let estimate = estimator
+            .estimate(
+                Address::from_low_u64_be(2),
+                Address::from_low_u64_be(1),
+                &[0xab, 0xcd],
+            )
+            .unwrap();
+        assert_eq!(estimate, U256::from(21_000));
+
+        let request: serde_json::Value =
+            serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(request["method"], "eth_estimateGas");
+        let call = &request["params"][0];
+        assert_eq!(call["from"], format!("0x{}{}", "0".repeat(39), "2"));
+        assert_eq!(call["to"], format!("0x{}{}", "0".repeat(39), "1"));
+        assert_eq!(call["data"], "0xabcd");
+    }
+}
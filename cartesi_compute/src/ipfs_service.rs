@@ -28,9 +28,11 @@
 //! together with the conversion functions from the automatically
 //! generated types.
 
+use super::compute_error::{ComputeError, ComputeResult};
 use super::ethereum_types::H256;
 use super::grpc::marshall::Marshaller;
 use super::ipfs_interface::ipfs;
+use std::convert::TryFrom;
 
 pub const IPFS_SERVICE_NAME: &'static str = "ipfs";
 pub const IPFS_METHOD_GET: &'static str = "/CartesiIpfs.Ipfs/GetFile";
@@ -69,14 +71,19 @@ pub struct GetFileResult {
     pub root_hash: H256,
 }
 
-impl From<ipfs::GetFileResponse_oneof_get_oneof> for GetFileResponseOneOf {
-    fn from(one_of: ipfs::GetFileResponse_oneof_get_oneof) -> Self {
+impl TryFrom<ipfs::GetFileResponse_oneof_get_oneof> for GetFileResponseOneOf {
+    type Error = ComputeError;
+
+    fn try_from(
+        one_of: ipfs::GetFileResponse_oneof_get_oneof,
+    ) -> ComputeResult<Self> {
         match one_of {
             ipfs::GetFileResponse_oneof_get_oneof::progress(s) => {
-                GetFileResponseOneOf::GetProgress(s.into())
+                Ok(GetFileResponseOneOf::GetProgress(s.into()))
             }
             ipfs::GetFileResponse_oneof_get_oneof::result(p) => {
-                GetFileResponseOneOf::GetResult(p.into())
+                let result = GetFileResult::try_from(p)?;
+                Ok(GetFileResponseOneOf::GetResult(result))
             }
         }
     }
@@ -91,26 +98,40 @@ impl From<ipfs::Progress> for Progress {
     }
 }
 
-impl From<ipfs::GetFileResult> for GetFileResult {
-    fn from(result: ipfs::GetFileResult) -> Self {
-        GetFileResult {
-            output_path: result.output_path,
-            root_hash: H256::from_slice(
-                &result
-                    .root_hash
-                    .into_option()
-                    .expect("root hash not found")
-                    .data,
-            ),
+impl TryFrom<ipfs::GetFileResult> for GetFileResult {
+    type Error = ComputeError;
+
+    fn try_from(result: ipfs::GetFileResult) -> ComputeResult<Self> {
+        let root_hash = result
+            .root_hash
+            .into_option()
+            .ok_or(invalid("root hash not found in GetFileResult".into()))?
+            .data;
+        if root_hash.len() != H256::len_bytes() {
+            return Err(invalid(format!(
+                "root hash in GetFileResult has {} bytes, expected {}",
+                root_hash.len(),
+                H256::len_bytes()
+            )));
         }
+
+        Ok(GetFileResult {
+            output_path: result.output_path,
+            root_hash: H256::from_slice(&root_hash),
+        })
     }
 }
 
-impl From<ipfs::GetFileResponse> for GetFileResponse {
-    fn from(response: ipfs::GetFileResponse) -> Self {
-        GetFileResponse {
-            one_of: response.get_oneof.unwrap().into(),
-        }
+impl TryFrom<ipfs::GetFileResponse> for GetFileResponse {
+    type Error = ComputeError;
+
+    fn try_from(response: ipfs::GetFileResponse) -> ComputeResult<Self> {
+        let one_of = response
+            .get_oneof
+            .ok_or(invalid("empty GetFileResponse".into()))?;
+        Ok(GetFileResponse {
+            one_of: GetFileResponseOneOf::try_from(one_of)?,
+        })
     }
 }
 
@@ -158,35 +179,55 @@ impl From<ipfs::AddFileResult> for AddFileResult {
     }
 }
 
-impl From<ipfs::AddFileResponse> for AddFileResponse {
-    fn from(response: ipfs::AddFileResponse) -> Self {
-        AddFileResponse {
-            one_of: response.add_oneof.unwrap().into(),
-        }
+impl TryFrom<ipfs::AddFileResponse> for AddFileResponse {
+    type Error = ComputeError;
+
+    fn try_from(response: ipfs::AddFileResponse) -> ComputeResult<Self> {
+        let one_of = response
+            .add_oneof
+            .ok_or(invalid("empty AddFileResponse".into()))?;
+        Ok(AddFileResponse {
+            one_of: one_of.into(),
+        })
     }
 }
 
-impl From<Vec<u8>> for GetFileResponse {
-    fn from(response: Vec<u8>) -> Self {
+impl TryFrom<Vec<u8>> for GetFileResponse {
+    type Error = ComputeError;
+
+    fn try_from(response: Vec<u8>) -> ComputeResult<Self> {
         let marshaller: Box<
             dyn Marshaller<ipfs::GetFileResponse> + Sync + Send,
         > = Box::new(grpc::protobuf::MarshallerProtobuf);
-        marshaller
+        let response = marshaller
             .read(bytes::Bytes::from(response))
-            .unwrap()
-            .into()
+            .map_err(|e| {
+                invalid(format!("could not read GetFileResponse: {:?}", e))
+            })?;
+        GetFileResponse::try_from(response)
     }
 }
 
-impl From<Vec<u8>> for AddFileResponse {
-    fn from(response: Vec<u8>) -> Self {
+impl TryFrom<Vec<u8>> for AddFileResponse {
+    type Error = ComputeError;
+
+    fn try_from(response: Vec<u8>) -> ComputeResult<Self> {
         let marshaller: Box<
             dyn Marshaller<ipfs::AddFileResponse> + Sync + Send,
         > = Box::new(grpc::protobuf::MarshallerProtobuf);
-        marshaller
+        let response = marshaller
             .read(bytes::Bytes::from(response))
-            .unwrap()
-            .into()
+            .map_err(|e| {
+                invalid(format!("could not read AddFileResponse: {:?}", e))
+            })?;
+        AddFileResponse::try_from(response)
+    }
+}
+
+fn invalid(reason: String) -> ComputeError {
+    ComputeError::InvalidResponse {
+        service: IPFS_SERVICE_NAME.into(),
+        reason: reason,
     }
 }
 
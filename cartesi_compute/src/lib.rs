@@ -22,12 +22,32 @@
 // Apache v2 license.
 
 #![warn(unused_extern_crates)]
+#[macro_use]
+pub mod abi;
+pub mod car;
 pub mod cartesi_compute;
+pub mod clock;
+pub mod compute_error;
+pub mod config;
+pub mod emulator;
+pub mod gas;
 pub mod ipfs_service;
+pub mod merkle;
+pub mod node;
+pub mod outbox;
+pub mod policy;
+pub mod proof;
+pub mod replay;
+pub mod results;
+pub mod schedule;
+pub mod session;
+pub mod templates;
 
 extern crate error;
 extern crate grpc;
 
+#[macro_use]
+extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
@@ -39,10 +59,20 @@ extern crate ethabi;
 extern crate ethereum_types;
 extern crate hex;
 extern crate ipfs_interface;
+extern crate keccak_hash;
 extern crate logger_service;
+extern crate protobuf;
+extern crate serde;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate sha2;
+extern crate tar;
 extern crate transaction;
+extern crate web3;
 
-pub use cartesi_compute::{CartesiCompute, CartesiComputeCtx, CartesiComputeCtxParsed};
+pub use cartesi_compute::{
+    CartesiCompute, CartesiComputeCtx, CartesiComputeCtxParsed, State,
+};
 
 pub use logger_service::{
     DownloadFileRequest, DownloadFileResponse, SubmitFileRequest,
@@ -52,15 +82,34 @@ pub use logger_service::{
 
 use ethereum_types::{Address, H256, U256};
 
-#[derive(Debug)]
+/// Part our address plays in an instance, from its party state
+#[derive(Debug, PartialEq)]
 enum Role {
+    /// Party whose claim is the current one, or who is due to claim
     Claimer,
+    /// Party whose challenge of the claim is being decided
     Challenger,
-    Other,
+    /// Party that has yet to confirm or challenge the current claim
+    Voter,
+    /// Party that has voted on the current claim, or lost a dispute
+    Voted,
+    /// Not a party, so it may not claim, vote or challenge drives
+    Observer,
+}
+
+impl Role {
+    fn is_party(&self) -> bool {
+        match self {
+            Role::Claimer | Role::Challenger | Role::Voter | Role::Voted => {
+                true
+            }
+            Role::Observer => false,
+        }
+    }
 }
 
 pub fn get_logger_response(
-    archive: &dispatcher::Archive,
+    archive: &dyn emulator::ServiceClient,
     contract: String,
     key: String,
     method: String,
@@ -144,10 +193,44 @@ pub fn build_logger_download_key(root_hash: H256) -> String {
     return format!("{:x}.logger.download", root_hash);
 }
 
+pub fn build_logger_provide_key(file_path: String) -> String {
+    return format!("{}.logger.provide", file_path);
+}
+
 pub fn build_ipfs_add_key(file_path: String) -> String {
-    return format!("{}.ipfs.get", file_path);
+    return format!("{}.ipfs.add", file_path);
 }
 
 pub fn build_ipfs_get_key(ipfs_path: String) -> String {
     return format!("{}.ipfs.get", ipfs_path);
 }
+
+/// Name of the files a node keeps for an instance it takes part in, as
+/// the same index belongs to instances of other contracts and users
+pub fn build_instance_file_name(
+    concern: &configuration::Concern,
+    cartesi_compute_index: U256,
+) -> String {
+    return format!(
+        "{:x}_{:x}_{}",
+        concern.contract_address,
+        concern.user_address,
+        cartesi_compute_index
+    );
+}
+
+/// Location of the content a provider node submits for one of its drives,
+/// laid out as `<inbox>/<instance file name>/<drive position in hex>`
+pub fn build_drive_inbox_path(
+    inbox: &str,
+    concern: &configuration::Concern,
+    cartesi_compute_index: U256,
+    position: U256,
+) -> String {
+    return format!(
+        "{}/{}/{:x}",
+        inbox,
+        build_instance_file_name(concern, cartesi_compute_index),
+        position
+    );
+}
@@ -28,22 +28,83 @@
 extern crate cartesi_compute;
 extern crate dispatcher;
 extern crate env_logger;
+extern crate error;
 extern crate utils;
 
-use cartesi_compute::CartesiCompute;
+use cartesi_compute::clock::{BlockClock, Clock, LocalClock};
+use cartesi_compute::config::ComputeConfig;
+use cartesi_compute::gas::{GasEstimator, NoGasEstimator, Web3GasEstimator};
+use cartesi_compute::node::{self, Node};
+use cartesi_compute::replay;
+use cartesi_compute::{CartesiCompute, CartesiComputeCtxParsed};
 use dispatcher::Dispatcher;
 use utils::print_error;
 
+use std::sync::Arc;
+
+/// Load and validate the cartesi compute sections of the dispatcher
+/// configuration, failing early if the deployed contract doesn't match
+/// what we parse
+fn load_config() -> error::Result<ComputeConfig> {
+    let config = ComputeConfig::load()?;
+    config.validate()?;
+    config.storage.create_dirs()?;
+    if let Some(main_concern) = &config.main_concern {
+        CartesiComputeCtxParsed::check_abi(&main_concern.abi)?;
+    }
+    Ok(config)
+}
+
+/// Set up the node with the loaded configuration
+fn load_node() -> error::Result<Node> {
+    let config = load_config()?;
+    // deadlines are decided on chain time, when the chain is known
+    let clock: Arc<dyn Clock> = match &config.url {
+        Some(url) => Arc::new(BlockClock::new(
+            url,
+            config.confirmations,
+            config.clock.local_fallback,
+        )?),
+        None => Arc::new(LocalClock),
+    };
+    let gas: Arc<dyn GasEstimator> = match &config.url {
+        Some(url) => Arc::new(Web3GasEstimator::new(url)?),
+        None => Arc::new(NoGasEstimator),
+    };
+    Ok(Node::new(config, clock, gas))
+}
+
 fn main() {
     env_logger::init();
 
+    // replay an instance offline instead of running the dispatcher
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|arg| arg.as_str()) == Some(replay::REPLAY_COMMAND) {
+        let replayed = load_config().and_then(|config| {
+            replay::run(config, &args[2..], &mut std::io::stdout())
+        });
+        if let Err(ref e) = replayed {
+            print_error(e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let dispatcher = match Dispatcher::new() {
         Ok(d) => d,
         Err(ref e) => {
             print_error(e);
-            return;
+            std::process::exit(1);
         }
     };
 
+    match load_node() {
+        Ok(node) => node::init(node),
+        Err(ref e) => {
+            print_error(e);
+            std::process::exit(1);
+        }
+    }
+
     dispatcher.run::<CartesiCompute>();
 }
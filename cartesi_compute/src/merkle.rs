@@ -0,0 +1,313 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Keccak Merkle tree over drive images, the same tree the logger and the
+//! CartesiCompute contract build for a drive.
+
+use super::error::*;
+use super::ethereum_types::H256;
+use super::keccak_hash::keccak;
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+
+/// Page size used by the logger and the contract, one machine word
+pub const WORD_LOG2_SIZE: u64 = 3;
+
+/// Root hash of a tree of `2^tree_log2_size` bytes holding `data` zero
+/// padded, whose leaves are the hashes of `2^page_log2_size` byte pages
+pub fn get_root_hash(
+    data: &[u8],
+    page_log2_size: u64,
+    tree_log2_size: u64,
+) -> Result<H256> {
+    get_reader_root_hash(&mut &data[..], page_log2_size, tree_log2_size)
+}
+
+/// Root hash of the drive image stored at `path`, read a page at a time
+pub fn get_file_root_hash(
+    path: &str,
+    page_log2_size: u64,
+    tree_log2_size: u64,
+) -> Result<H256> {
+    let file = File::open(path)
+        .chain_err(|| format!("Could not read drive image {}", path))?;
+    get_reader_root_hash(
+        &mut BufReader::new(file),
+        page_log2_size,
+        tree_log2_size,
+    )
+    .chain_err(|| format!("Could not hash drive image {}", path))
+}
+
+/// Root hash of the tree holding everything `reader` yields zero padded,
+/// keeping only one page and a hash per level in memory
+pub fn get_reader_root_hash(
+    reader: &mut dyn Read,
+    page_log2_size: u64,
+    tree_log2_size: u64,
+) -> Result<H256> {
+    if page_log2_size > tree_log2_size {
+        return Err(format!(
+            "Page log2 size {} is larger than tree log2 size {}",
+            page_log2_size, tree_log2_size
+        )
+        .into());
+    }
+    if tree_log2_size >= 64 {
+        return Err(format!(
+            "Tree log2 size {} is too large",
+            tree_log2_size
+        )
+        .into());
+    }
+
+    let mut builder = TreeBuilder::new(page_log2_size, tree_log2_size);
+    let mut page = vec![0u8; 1 << page_log2_size];
+    let mut size = 0u64;
+    loop {
+        let read = read_page(reader, &mut page)?;
+        if read == 0 {
+            break;
+        }
+        size += read as u64;
+        if size > 1 << tree_log2_size {
+            return Err(format!(
+                "Drive data has more than {} bytes, exceeding log2 size {}",
+                1u64 << tree_log2_size,
+                tree_log2_size
+            )
+            .into());
+        }
+        // the last page is zero padded
+        for byte in page[read..].iter_mut() {
+            *byte = 0;
+        }
+        builder.push(keccak(&page));
+    }
+    Ok(builder.finish())
+}
+
+/// Root hash of a zero filled drive of `2^log2_size` bytes, as
+/// `Merkle.getPristineHash` computes it
+pub fn get_pristine_hash(log2_size: u64) -> Result<H256> {
+    get_root_hash(&[], WORD_LOG2_SIZE, log2_size)
+}
+
+/// Root hash of the machine whose drive of `2^log2_size` bytes at
+/// `position` hashes to `drive_hash`, given the siblings of the drive from
+/// the bottom up, as `Merkle.getRootWithDrive` computes it
+pub fn get_root_with_drive(
+    position: u64,
+    log2_size: u64,
+    drive_hash: H256,
+    siblings: &[H256],
+) -> Result<H256> {
+    if log2_size < WORD_LOG2_SIZE || siblings.len() as u64 + log2_size != 64 {
+        return Err(format!(
+            "Drive of log2 size {} can't have {} siblings",
+            log2_size,
+            siblings.len()
+        )
+        .into());
+    }
+
+    let mut root = drive_hash;
+    for (i, sibling) in siblings.iter().enumerate() {
+        root = if (position >> (log2_size + i as u64)) & 1 == 0 {
+            hash_pair(&root, sibling)
+        } else {
+            hash_pair(sibling, &root)
+        };
+    }
+    Ok(root)
+}
+
+/// Root hashes of zero filled trees, indexed by their log2 size
+fn get_pristine_hashes(page_log2_size: u64, tree_log2_size: u64) -> Vec<H256> {
+    let mut hashes = vec![H256::zero(); (tree_log2_size + 1) as usize];
+    hashes[page_log2_size as usize] =
+        keccak(vec![0u8; 1 << page_log2_size]);
+    for i in (page_log2_size + 1) as usize..hashes.len() {
+        hashes[i] = hash_pair(&hashes[i - 1], &hashes[i - 1]);
+    }
+    hashes
+}
+
+/// Fill `page` from `reader`, returning how many bytes it got, which is
+/// less than the page size only at the end of the data
+fn read_page(reader: &mut dyn Read, page: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < page.len() {
+        match reader.read(&mut page[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(read)
+}
+
+/// Tree built from its leaves left to right, holding the root of every
+/// complete left subtree still waiting for its right sibling
+struct TreeBuilder {
+    page_log2_size: u64,
+    tree_log2_size: u64,
+    pristine_hashes: Vec<H256>,
+    pending: Vec<Option<H256>>,
+}
+
+impl TreeBuilder {
+    fn new(page_log2_size: u64, tree_log2_size: u64) -> Self {
+        TreeBuilder {
+            page_log2_size: page_log2_size,
+            tree_log2_size: tree_log2_size,
+            pristine_hashes: get_pristine_hashes(
+                page_log2_size,
+                tree_log2_size,
+            ),
+            pending: vec![None; (tree_log2_size + 1) as usize],
+        }
+    }
+
+    fn push(&mut self, leaf: H256) {
+        let mut hash = leaf;
+        let mut level = self.page_log2_size as usize;
+        while let Some(left) = self.pending[level].take() {
+            hash = hash_pair(&left, &hash);
+            level += 1;
+        }
+        self.pending[level] = Some(hash);
+    }
+
+    /// Root hash, with every leaf not pushed being a zero page
+    fn finish(self) -> H256 {
+        let top = self.tree_log2_size as usize;
+        if let Some(root) = self.pending[top] {
+            return root;
+        }
+
+        // the rightmost subtree holding data, at the current level
+        let mut right: Option<H256> = None;
+        for level in self.page_log2_size as usize..top {
+            let pristine = &self.pristine_hashes[level];
+            right = match (&self.pending[level], &right) {
+                (Some(left), Some(right)) => Some(hash_pair(left, right)),
+                (Some(left), None) => Some(hash_pair(left, pristine)),
+                (None, Some(right)) => Some(hash_pair(right, pristine)),
+                (None, None) => None,
+            };
+        }
+        right.unwrap_or(self.pristine_hashes[top])
+    }
+}
+
+/// Hash of a node from the hashes of its children
+pub fn hash_pair(left: &H256, right: &H256) -> H256 {
+    keccak([left.as_bytes(), right.as_bytes()].concat())
+}
+
+/// Fixtures shared with the tests of the modules handling proofs
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Leaf hash of a word holding `data` zero padded
+    pub fn word(data: &[u8]) -> H256 {
+        let mut page = [0u8; 8];
+        page[..data.len()].copy_from_slice(data);
+        keccak(&page[..])
+    }
+
+    /// Content at address 0 of the fixture machine, zero elsewhere
+    pub fn get_machine_data() -> Vec<u8> {
+        (0..20).collect()
+    }
+
+    /// Root hash of the fixture machine, built as a tree of its halves
+    pub fn get_machine_root() -> H256 {
+        let left = get_root_hash(&get_machine_data(), WORD_LOG2_SIZE, 63);
+        hash_pair(&left.unwrap(), &get_pristine_hash(63).unwrap())
+    }
+
+    /// Siblings of the word at address 8 of the fixture machine, from
+    /// the bottom up
+    pub fn get_word_siblings() -> Vec<H256> {
+        let data = get_machine_data();
+        let mut siblings =
+            vec![word(&data[..8]), hash_pair(&word(&data[16..]), &word(&[]))];
+        for log2_size in 5..64 {
+            siblings.push(get_pristine_hash(log2_size).unwrap());
+        }
+        siblings
+    }
+
+    /// Reader handing out a few bytes at a time
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = std::cmp::min(std::cmp::min(3, buf.len()), self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn hashes_words_as_leaves() {
+        let data: Vec<u8> = (0..20).collect();
+        let expected = hash_pair(
+            &hash_pair(&word(&data[..8]), &word(&data[8..16])),
+            &hash_pair(&word(&data[16..]), &word(&[])),
+        );
+        assert_eq!(get_root_hash(&data, WORD_LOG2_SIZE, 5).unwrap(), expected);
+        assert_eq!(
+            get_reader_root_hash(&mut Trickle(&data), WORD_LOG2_SIZE, 5)
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn pads_with_pristine_subtrees() {
+        let data = [1u8; 8];
+        let mut expected = word(&data);
+        for log2_size in WORD_LOG2_SIZE..20 {
+            expected =
+                hash_pair(&expected, &get_pristine_hash(log2_size).unwrap());
+        }
+        assert_eq!(get_root_hash(&data, WORD_LOG2_SIZE, 20).unwrap(), expected);
+        assert_eq!(
+            get_root_hash(&[], WORD_LOG2_SIZE, 20).unwrap(),
+            get_pristine_hash(20).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_data_larger_than_the_tree() {
+        assert!(get_root_hash(&[0u8; 16], WORD_LOG2_SIZE, 4).is_ok());
+        assert!(get_root_hash(&[0u8; 17], WORD_LOG2_SIZE, 4).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! What the node works with besides the instance it reacts to.
+//!
+//! The dispatcher calls into the dapp without any state of its own, so
+//! the configuration, the clock and what is kept between reactions are
+//! set up once at startup with `init`, and `CartesiCompute` takes them
+//! from here only at its entry points. Everything below gets them as a
+//! `Node` argument, so tests and the replay command build their own.
+
+use super::cartesi_compute::VerifiedDrives;
+use super::clock::{Clock, LocalClock};
+use super::config::ComputeConfig;
+use super::gas::{GasEstimator, NoGasEstimator};
+use super::schedule::Scheduler;
+use super::session::SessionManager;
+use super::templates::VerifiedTemplates;
+
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    static ref NODE: RwLock<Node> = RwLock::new(Node::new(
+        ComputeConfig::default(),
+        Arc::new(LocalClock),
+        Arc::new(NoGasEstimator)
+    ));
+}
+
+#[derive(Clone)]
+pub struct Node {
+    pub config: Arc<ComputeConfig>,
+    /// Clock every deadline decision is taken with
+    pub clock: Arc<dyn Clock>,
+    /// Ethereum node estimating the gas of the transactions
+    pub gas: Arc<dyn GasEstimator>,
+    /// Emulator sessions kept alive across reactions
+    pub sessions: Arc<SessionManager>,
+    /// Machines waiting for or doing work
+    pub scheduler: Arc<Scheduler>,
+    /// Drive images already checked against their root hash
+    pub verified_drives: Arc<VerifiedDrives>,
+    /// Templates already loaded with their hash as root hash
+    pub verified_templates: Arc<VerifiedTemplates>,
+}
+
+impl Node {
+    /// A node with nothing kept from previous reactions
+    pub fn new(
+        config: ComputeConfig,
+        clock: Arc<dyn Clock>,
+        gas: Arc<dyn GasEstimator>,
+    ) -> Self {
+        Node {
+            sessions: Arc::new(SessionManager::new(
+                config.sessions.max_sessions,
+            )),
+            scheduler: Arc::new(Scheduler::new(
+                config.schedule.max_concurrent_runs,
+            )),
+            verified_drives: Arc::new(VerifiedDrives::default()),
+            verified_templates: Arc::new(VerifiedTemplates::default()),
+            config: Arc::new(config),
+            clock: clock,
+            gas: gas,
+        }
+    }
+}
+
+/// Make `node` the one the dispatcher entry points react with
+pub fn init(node: Node) {
+    *NODE.write().unwrap() = node;
+}
+
+/// The node set up at startup, with the default configuration, the
+/// local clock and no gas estimates until `init` is called
+pub fn get() -> Node {
+    NODE.read().unwrap().clone()
+}
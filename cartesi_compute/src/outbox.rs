@@ -0,0 +1,442 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Transactions held back from the chain.
+//!
+//! In dry-run mode the transactions the node decides on are recorded
+//! here, with their arguments decoded, and never sent. In approval mode
+//! each one waits here until an operator approves it by posting
+//! `{"approve": "<id>"}` to the instance through the query port, and is
+//! sent from then on. Each instance keeps its queue in a json file in the
+//! outbox directory, named after its contract and user as well as its
+//! index, so approvals survive restarts.
+
+use super::abi;
+use super::build_instance_file_name;
+use super::config::{ComputeConfig, Mode, StorageConfig};
+use super::configuration::Concern;
+use super::dispatcher::Reaction;
+use super::error::*;
+use super::ethabi::{self, Token};
+use super::ethereum_types::{H256, U256};
+use super::keccak_hash::keccak;
+use super::transaction::TransactionRequest;
+use super::{hex, serde_json};
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A transaction the node intends to send
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeldTransaction {
+    /// Hash of the function name and encoded arguments, by which
+    /// operators approve the transaction
+    pub id: H256,
+    pub function: String,
+    pub arguments: Vec<Argument>,
+    /// Seconds since the epoch when the node first decided on it
+    pub created: u64,
+    pub approved: bool,
+}
+
+/// Argument of a held transaction, named as in the contract ABI
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Argument {
+    pub name: String,
+    pub value: serde_json::Value,
+}
+
+impl HeldTransaction {
+    fn new(
+        config: &ComputeConfig,
+        request: &TransactionRequest,
+    ) -> Result<Self> {
+        let mut preimage = request.function.clone().into_bytes();
+        preimage.extend(ethabi::encode(&request.data));
+
+        let names = get_argument_names(config, &request.function);
+        let arguments = request
+            .data
+            .iter()
+            .enumerate()
+            .map(|(i, token)| Argument {
+                name: names
+                    .get(i)
+                    .filter(|name| !name.is_empty())
+                    .cloned()
+                    .unwrap_or(i.to_string()),
+                value: decode_token(token),
+            })
+            .collect();
+
+        Ok(HeldTransaction {
+            id: keccak(preimage),
+            function: request.function.clone(),
+            arguments: arguments,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .chain_err(|| "System time before UNIX_EPOCH")?
+                .as_secs(),
+            approved: false,
+        })
+    }
+}
+
+/// Payload accepted from the query port
+#[derive(Deserialize)]
+struct Approval {
+    approve: H256,
+}
+
+/// Input names of `function` in the cartesi compute ABI, empty for the
+/// functions of other contracts
+fn get_argument_names(
+    config: &ComputeConfig,
+    function: &str,
+) -> Vec<String> {
+    let abi_path = match &config.main_concern {
+        Some(concern) => &concern.abi,
+        None => return vec![],
+    };
+    abi::get_function_inputs(abi_path, function).unwrap_or_else(|e| {
+        trace!("No argument names for {}: {}", function, e);
+        vec![]
+    })
+}
+
+/// Token as json, with numbers in decimal and bytes in hex
+fn decode_token(token: &Token) -> serde_json::Value {
+    match token {
+        Token::Uint(n) | Token::Int(n) => n.to_string().into(),
+        Token::FixedBytes(b) | Token::Bytes(b) => {
+            format!("0x{}", hex::encode(b)).into()
+        }
+        Token::Address(a) => format!("0x{:x}", a).into(),
+        Token::Bool(b) => (*b).into(),
+        Token::String(s) => s.clone().into(),
+        Token::FixedArray(tokens) | Token::Array(tokens) => {
+            tokens.iter().map(decode_token).collect()
+        }
+        other => other.to_string().into(),
+    }
+}
+
+fn get_outbox_path(
+    storage: &StorageConfig,
+    concern: &Concern,
+    index: U256,
+) -> String {
+    format!(
+        "{}/{}.json",
+        storage.outbox_dir(),
+        build_instance_file_name(concern, index)
+    )
+}
+
+/// Transactions held for an instance
+pub fn load(
+    storage: &StorageConfig,
+    concern: &Concern,
+    index: U256,
+) -> Result<Vec<HeldTransaction>> {
+    let path = get_outbox_path(storage, concern, index);
+    if !Path::new(&path).exists() {
+        return Ok(vec![]);
+    }
+    let json = std::fs::read(&path)
+        .chain_err(|| format!("Could not read held transactions {}", path))?;
+    serde_json::from_slice(&json)
+        .chain_err(|| format!("Could not parse held transactions {}", path))
+}
+
+fn store(
+    storage: &StorageConfig,
+    concern: &Concern,
+    index: U256,
+    held: &Vec<HeldTransaction>,
+) -> Result<()> {
+    let path = get_outbox_path(storage, concern, index);
+    let json = serde_json::to_vec_pretty(held)
+        .chain_err(|| "Could not serialize held transactions")?;
+    std::fs::write(&path, json)
+        .chain_err(|| format!("Could not write held transactions {}", path))
+}
+
+/// Approve the held transaction named by `payload`, as posted to the
+/// instance through the query port
+pub fn approve(
+    storage: &StorageConfig,
+    concern: &Concern,
+    index: U256,
+    payload: &str,
+) -> Result<()> {
+    let approval: Approval = serde_json::from_str(payload)
+        .chain_err(|| format!("Invalid query payload {}", payload))?;
+    let mut held = load(storage, concern, index)?;
+    {
+        let transaction = held
+            .iter_mut()
+            .find(|transaction| transaction.id == approval.approve)
+            .ok_or(Error::from(format!(
+                "No transaction {:x} held for instance {}",
+                approval.approve, index
+            )))?;
+        transaction.approved = true;
+        info!(
+            "Transaction {:x} ({}) of Cartesi Compute (index: {}) approved",
+            transaction.id, transaction.function, index
+        );
+    }
+    store(storage, concern, index, &held)
+}
+
+/// Pass `reaction` on as far as the mode of the node allows, holding
+/// back its transaction unless the mode sends it
+pub fn filter(
+    config: &ComputeConfig,
+    concern: &Concern,
+    index: U256,
+    reaction: Reaction,
+) -> Result<Reaction> {
+    let request = match reaction {
+        Reaction::Transaction(request) => request,
+        other => return Ok(other),
+    };
+    let mode = config.mode;
+    match mode {
+        Mode::Active => return Ok(Reaction::Transaction(request)),
+        Mode::Watch => {
+            error!(
+                "Watching node decided on {} for Cartesi Compute (index: {}), not sending",
+                request.function, index
+            );
+            return Ok(Reaction::Idle);
+        }
+        Mode::DryRun | Mode::Approval => {}
+    }
+
+    let transaction = HeldTransaction::new(config, &request)?;
+    let mut held = load(&config.storage, concern, index)?;
+    // a change of state makes the node decide on another transaction,
+    // superseding the ones held before
+    if held.len() != 1 || held[0].id != transaction.id {
+        held.retain(|t| t.id == transaction.id);
+        if held.is_empty() {
+            info!(
+                "Holding {} of Cartesi Compute (index: {}) as {:x}, {}: {}",
+                transaction.function,
+                index,
+                transaction.id,
+                if mode == Mode::DryRun {
+                    "dry run"
+                } else {
+                    "waiting for approval"
+                },
+                serde_json::to_string(&transaction.arguments)
+                    .chain_err(|| "Could not serialize transaction arguments")?
+            );
+            held.push(transaction);
+        }
+        store(&config.storage, concern, index, &held)?;
+    }
+
+    if mode == Mode::Approval && held[0].approved {
+        return Ok(Reaction::Transaction(request));
+    }
+    Ok(Reaction::Idle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::ConcernConfig;
+    use super::super::ethereum_types::Address;
+    use super::super::merkle::tests::{get_machine_root, get_word_siblings};
+    use super::super::transaction;
+    use super::*;
+
+    const INDEX: u64 = 7;
+
+    /// Node in `mode` holding transactions in a directory of its own
+    fn get_config(name: &str, mode: Mode) -> ComputeConfig {
+        let mut config = ComputeConfig::default();
+        config.mode = mode;
+        config.storage.outbox_path = std::env::temp_dir()
+            .join(format!("cartesi-outbox-{}-{}", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        std::fs::create_dir_all(config.storage.outbox_dir()).unwrap();
+        config.main_concern = Some(ConcernConfig {
+            abi: Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../deployments/goerli/CartesiCompute.json")
+                .to_string_lossy()
+                .into_owned(),
+        });
+        config
+    }
+
+    fn get_concern() -> Concern {
+        Concern {
+            contract_address: Address::from_low_u64_be(1),
+            user_address: Address::from_low_u64_be(2),
+        }
+    }
+
+    fn get_request(function: &str, data: Vec<Token>) -> Reaction {
+        Reaction::Transaction(TransactionRequest {
+            contract_name: None,
+            concern: get_concern(),
+            value: U256::zero(),
+            function: function.into(),
+            data: data,
+            gas: None,
+            strategy: transaction::Strategy::Simplest,
+        })
+    }
+
+    /// Claim of the fixture machine, with the siblings of its output
+    fn get_claim() -> Reaction {
+        let siblings = Token::Array(
+            get_word_siblings()
+                .iter()
+                .map(|hash| Token::FixedBytes(hash.0.to_vec()))
+                .collect(),
+        );
+        get_request(
+            "submitClaim",
+            vec![
+                Token::Uint(INDEX.into()),
+                Token::FixedBytes(get_machine_root().0.to_vec()),
+                Token::Array(vec![]),
+                Token::Bytes(vec![8, 9]),
+                siblings,
+            ],
+        )
+    }
+
+    fn filter_claim(config: &ComputeConfig) -> Reaction {
+        filter(config, &get_concern(), INDEX.into(), get_claim()).unwrap()
+    }
+
+    fn load_held(config: &ComputeConfig) -> Vec<HeldTransaction> {
+        load(&config.storage, &get_concern(), INDEX.into()).unwrap()
+    }
+
+    fn get_approval(id: H256) -> String {
+        format!("{{\"approve\": \"0x{}\"}}", hex::encode(id.as_bytes()))
+    }
+
+    fn is_sent(reaction: &Reaction) -> bool {
+        match reaction {
+            Reaction::Transaction(_) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn holds_claims_with_named_decoded_arguments() {
+        let config = get_config("held", Mode::DryRun);
+        assert!(!is_sent(&filter_claim(&config)));
+
+        let held = load_held(&config);
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].function, "submitClaim");
+        assert!(!held[0].approved);
+        let arguments = &held[0].arguments;
+        assert_eq!(arguments[0].name, "_index");
+        assert_eq!(arguments[0].value, serde_json::Value::from("7"));
+        assert_eq!(arguments[1].name, "_claimedFinalHash");
+        assert_eq!(
+            arguments[1].value,
+            serde_json::Value::from(format!(
+                "0x{}",
+                hex::encode(get_machine_root().as_bytes())
+            ))
+        );
+        let siblings: Vec<String> = get_word_siblings()
+            .iter()
+            .map(|hash| format!("0x{}", hex::encode(hash.as_bytes())))
+            .collect();
+        assert_eq!(arguments[4].name, "_outputSiblings");
+        assert_eq!(arguments[4].value, serde_json::Value::from(siblings));
+
+        // the same claim is held once, under the same id
+        assert!(!is_sent(&filter_claim(&config)));
+        let again = load_held(&config);
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].id, held[0].id);
+        assert_eq!(again[0].created, held[0].created);
+    }
+
+    #[test]
+    fn sends_claims_once_approved() {
+        let config = get_config("approved", Mode::Approval);
+        assert!(!is_sent(&filter_claim(&config)));
+
+        let id = load_held(&config)[0].id;
+        approve(
+            &config.storage,
+            &get_concern(),
+            INDEX.into(),
+            &get_approval(id),
+        )
+        .unwrap();
+        assert!(load_held(&config)[0].approved);
+        assert!(is_sent(&filter_claim(&config)));
+
+        // another transaction supersedes the approved one
+        let confirm = get_request("confirm", vec![Token::Uint(INDEX.into())]);
+        let reaction =
+            filter(&config, &get_concern(), INDEX.into(), confirm).unwrap();
+        assert!(!is_sent(&reaction));
+        let held = load_held(&config);
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].function, "confirm");
+        assert!(!held[0].approved);
+    }
+
+    #[test]
+    fn rejects_approvals_of_unknown_transactions() {
+        let config = get_config("unknown", Mode::Approval);
+        filter_claim(&config);
+        let approval = get_approval(H256::repeat_byte(1));
+        assert!(approve(
+            &config.storage,
+            &get_concern(),
+            INDEX.into(),
+            &approval
+        )
+        .is_err());
+        assert!(!load_held(&config)[0].approved);
+    }
+
+    #[test]
+    fn never_holds_in_active_or_watch_mode() {
+        let active = get_config("active", Mode::Active);
+        assert!(is_sent(&filter_claim(&active)));
+        assert!(load_held(&active).is_empty());
+
+        let watching = get_config("watching", Mode::Watch);
+        assert!(!is_sent(&filter_claim(&watching)));
+        assert!(load_held(&watching).is_empty());
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Admission policy for the instances this node works on.
+//!
+//! Anyone can instantiate the contract with this node as a party, so the
+//! operator limits what an instance may cost before the node spends any
+//! emulator time or downloads anything for it.
+
+use super::cartesi_compute::CartesiComputeCtx;
+use super::config::{Mode, PolicyConfig};
+use super::ethereum_types::{Address, H256, U256};
+
+use std::fmt;
+
+/// A limit of the policy the instance does not respect
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum Violation {
+    Cycles { final_time: U256, max_cycles: u64 },
+    DriveSize { position: U256, size: u64, max_drive_size: u64 },
+    TotalDownload { total: u64, max_total_download: u64 },
+    Template(H256),
+    Counterparty(Address),
+}
+
+impl Violation {
+    /// Whether the violation is of a limit on the work and downloads of
+    /// the instance, rather than on whom the node works with
+    pub fn is_resource_limit(&self) -> bool {
+        match self {
+            Violation::Cycles { .. }
+            | Violation::DriveSize { .. }
+            | Violation::TotalDownload { .. } => true,
+            Violation::Template(_) | Violation::Counterparty(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Cycles {
+                final_time,
+                max_cycles,
+            } => write!(
+                f,
+                "final time {} exceeds {} cycles",
+                final_time, max_cycles
+            ),
+            Violation::DriveSize {
+                position,
+                size,
+                max_drive_size,
+            } => write!(
+                f,
+                "drive at position {:x} has {} bytes, exceeding {}",
+                position, size, max_drive_size
+            ),
+            Violation::TotalDownload {
+                total,
+                max_total_download,
+            } => write!(
+                f,
+                "drives to download add up to {} bytes, exceeding {}",
+                total, max_total_download
+            ),
+            Violation::Template(hash) => {
+                write!(f, "template {:x} is not allowed", hash)
+            }
+            Violation::Counterparty(address) => {
+                write!(f, "counterparty {:x} is not allowed", address)
+            }
+        }
+    }
+}
+
+/// Every limit of the configured policy the instance does not respect,
+/// seen from `user_address`.
+///
+/// While waiting for providers or reveals `getState` returns only the
+/// pending drive, so the drive limits see every drive from the next state
+/// on, before any of them is downloaded.
+pub fn check(
+    policy: &PolicyConfig,
+    ctx: &CartesiComputeCtx,
+    user_address: Address,
+) -> Vec<Violation> {
+    let mut violations = vec![];
+
+    if let Some(max_cycles) = policy.max_cycles {
+        if ctx.final_time > U256::from(max_cycles) {
+            violations.push(Violation::Cycles {
+                final_time: ctx.final_time,
+                max_cycles: max_cycles,
+            });
+        }
+    }
+
+    let mut total_download: u64 = 0;
+    for drive in &ctx.input_drives {
+        let size = get_drive_size(drive.log2_size);
+        if let Some(max_drive_size) = policy.max_drive_size {
+            if size > max_drive_size {
+                violations.push(Violation::DriveSize {
+                    position: drive.position,
+                    size: size,
+                    max_drive_size: max_drive_size,
+                });
+            }
+        }
+        // the drives we provide are already here
+        if drive.needs_logger && drive.provider != user_address {
+            total_download = total_download.saturating_add(size);
+        }
+    }
+    if let Some(max_total_download) = policy.max_total_download {
+        if total_download > max_total_download {
+            violations.push(Violation::TotalDownload {
+                total: total_download,
+                max_total_download: max_total_download,
+            });
+        }
+    }
+
+    if let Some(allowed) = &policy.allowed_templates {
+        if !allowed.contains(&ctx.template_hash) {
+            violations.push(Violation::Template(ctx.template_hash));
+        }
+    }
+
+    if let Some(allowed) = &policy.allowed_counterparties {
+        let mut counterparties = vec![ctx.claimer, ctx.challenger];
+        counterparties
+            .extend(ctx.input_drives.iter().map(|drive| drive.provider));
+        for address in counterparties {
+            if address == user_address
+                || address == Address::zero()
+                || allowed.contains(&address)
+            {
+                continue;
+            }
+            let violation = Violation::Counterparty(address);
+            if !violations.contains(&violation) {
+                violations.push(violation);
+            }
+        }
+    }
+
+    violations
+}
+
+/// The violations keeping a node in `mode` from working on the instance.
+/// A watching node audits instances of any template or counterparty, but
+/// within the resource limits.
+pub fn get_rejections(
+    policy: &PolicyConfig,
+    mode: Mode,
+    ctx: &CartesiComputeCtx,
+    user_address: Address,
+) -> Vec<Violation> {
+    let violations = check(policy, ctx, user_address);
+    if mode != Mode::Watch {
+        return violations;
+    }
+    violations
+        .into_iter()
+        .filter(Violation::is_resource_limit)
+        .collect()
+}
+
+/// Size in bytes of a drive, saturating for sizes beyond 64 bits
+pub fn get_drive_size(log2_size: U256) -> u64 {
+    if log2_size >= U256::from(64) {
+        return u64::max_value();
+    }
+    1u64 << log2_size.as_u64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::cartesi_compute::{Drive, PartyParsed, State};
+    use super::*;
+
+    const USER: u64 = 1;
+    const CLAIMER: u64 = 2;
+    const FINAL_TIME: u64 = 1000;
+    const LOG2_SIZE: u64 = 10;
+
+    fn get_drive(needs_logger: bool) -> Drive {
+        Drive {
+            position: U256::from(1u64 << 32),
+            log2_size: LOG2_SIZE.into(),
+            direct_value: vec![],
+            ipfs_path: String::new(),
+            root_hash: H256::zero(),
+            provider: Address::from_low_u64_be(CLAIMER),
+            waits_provider: false,
+            needs_logger: needs_logger,
+            download_as_car: false,
+        }
+    }
+
+    fn get_ctx() -> CartesiComputeCtx {
+        CartesiComputeCtx {
+            template_hash: H256::repeat_byte(1),
+            initial_hash: H256::zero(),
+            claimed_final_hash: H256::zero(),
+            claimed_output: vec![],
+            claimer: Address::from_low_u64_be(CLAIMER),
+            challenger: Address::zero(),
+            deadline: 0.into(),
+            output_position: 0.into(),
+            output_log2_size: 0.into(),
+            final_time: FINAL_TIME.into(),
+            current_state: State::WaitingClaim,
+            input_drives: vec![get_drive(true)],
+            partyState: PartyParsed {
+                is_party: true,
+                has_voted: false,
+                has_cheated: false,
+                array_idx: 1.into(),
+            }
+            .into(),
+            noChallengeDrive: false,
+        }
+    }
+
+    fn get_strict_policy() -> PolicyConfig {
+        PolicyConfig {
+            max_cycles: Some(FINAL_TIME - 1),
+            max_drive_size: None,
+            max_total_download: None,
+            allowed_templates: Some(vec![]),
+            allowed_counterparties: Some(vec![]),
+        }
+    }
+
+    #[test]
+    fn watching_keeps_only_the_resource_limits() {
+        let policy = get_strict_policy();
+        let user = Address::from_low_u64_be(USER);
+
+        let active = get_rejections(&policy, Mode::Active, &get_ctx(), user);
+        assert_eq!(active.len(), 3);
+
+        let watching = get_rejections(&policy, Mode::Watch, &get_ctx(), user);
+        assert_eq!(
+            watching,
+            vec![Violation::Cycles {
+                final_time: FINAL_TIME.into(),
+                max_cycles: FINAL_TIME - 1,
+            }]
+        );
+    }
+
+    /// Violations of a policy setting a single limit with `set`
+    fn check_with(set: &dyn Fn(&mut PolicyConfig)) -> Vec<Violation> {
+        check_ctx_with(&get_ctx(), set)
+    }
+
+    fn check_ctx_with(
+        ctx: &CartesiComputeCtx,
+        set: &dyn Fn(&mut PolicyConfig),
+    ) -> Vec<Violation> {
+        let mut policy = PolicyConfig::default();
+        set(&mut policy);
+        check(&policy, ctx, Address::from_low_u64_be(USER))
+    }
+
+    #[test]
+    fn allows_anything_without_limits() {
+        assert!(check_with(&|_| {}).is_empty());
+    }
+
+    #[test]
+    fn limits_the_cycles() {
+        for &max_cycles in &[FINAL_TIME, FINAL_TIME + 1] {
+            let violations = check_with(&|p| p.max_cycles = Some(max_cycles));
+            assert!(violations.is_empty());
+        }
+        assert_eq!(
+            check_with(&|p| p.max_cycles = Some(FINAL_TIME - 1)),
+            vec![Violation::Cycles {
+                final_time: FINAL_TIME.into(),
+                max_cycles: FINAL_TIME - 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn limits_the_size_of_each_drive() {
+        let size = 1 << LOG2_SIZE;
+        for &max_drive_size in &[size, size + 1] {
+            assert!(check_with(&|p| p.max_drive_size = Some(max_drive_size))
+                .is_empty());
+        }
+        assert_eq!(
+            check_with(&|p| p.max_drive_size = Some(size - 1)),
+            vec![Violation::DriveSize {
+                position: get_drive(true).position,
+                size: size,
+                max_drive_size: size - 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn limits_the_total_download() {
+        // neither direct drives nor the drives we provide are downloaded
+        let mut ctx = get_ctx();
+        let mut provided = get_drive(true);
+        provided.provider = Address::from_low_u64_be(USER);
+        ctx.input_drives =
+            vec![get_drive(true), get_drive(true), get_drive(false), provided];
+
+        let total = 2 << LOG2_SIZE;
+        for &max_total_download in &[total, total + 1] {
+            assert!(check_ctx_with(&ctx, &|p| {
+                p.max_total_download = Some(max_total_download)
+            })
+            .is_empty());
+        }
+        assert_eq!(
+            check_ctx_with(&ctx, &|p| p.max_total_download = Some(total - 1)),
+            vec![Violation::TotalDownload {
+                total: total,
+                max_total_download: total - 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn allows_only_the_listed_templates() {
+        let template_hash = get_ctx().template_hash;
+        assert!(check_with(&|p| {
+            p.allowed_templates = Some(vec![H256::zero(), template_hash])
+        })
+        .is_empty());
+        for allowed in vec![vec![], vec![H256::zero()]] {
+            assert_eq!(
+                check_with(&|p| p.allowed_templates = Some(allowed.clone())),
+                vec![Violation::Template(template_hash)]
+            );
+        }
+    }
+
+    #[test]
+    fn allows_only_the_listed_counterparties() {
+        let claimer = Address::from_low_u64_be(CLAIMER);
+        assert!(check_with(&|p| {
+            p.allowed_counterparties = Some(vec![claimer])
+        })
+        .is_empty());
+        // the claimer also provides the drive, and is reported once; we
+        // and the zero challenger are never counterparties
+        for allowed in vec![vec![], vec![Address::from_low_u64_be(3)]] {
+            assert_eq!(
+                check_with(&|p| {
+                    p.allowed_counterparties = Some(allowed.clone())
+                }),
+                vec![Violation::Counterparty(claimer)]
+            );
+        }
+
+        let mut ctx = get_ctx();
+        ctx.claimer = Address::from_low_u64_be(USER);
+        ctx.input_drives = vec![];
+        assert!(check_ctx_with(&ctx, &|p| {
+            p.allowed_counterparties = Some(vec![])
+        })
+        .is_empty());
+    }
+}
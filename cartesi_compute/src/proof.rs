@@ -0,0 +1,185 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Merkle proofs in the layout `CartesiCompute.sol` expects.
+//!
+//! The contract folds siblings from the target up to the root, while the
+//! machine manager has listed them from the root down. Rather than trust
+//! either order, the siblings are tried both ways against the target and
+//! root hashes of the proof, and only a layout that reproduces the root
+//! is ever submitted.
+
+use super::emulator::Proof;
+use super::error::*;
+use super::ethabi::Token;
+use super::ethereum_types::H256;
+use super::merkle;
+
+/// Siblings of a verified proof, from the target up to the root
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContractProof {
+    pub target_hash: H256,
+    pub siblings: Vec<H256>,
+    pub root_hash: H256,
+}
+
+impl ContractProof {
+    /// Take `proof` of the range of `2^log2_size` bytes at `address`,
+    /// in whichever order the emulator listed its siblings, failing
+    /// unless the siblings lead from the target hash to the root hash
+    pub fn new(proof: &Proof, address: u64, log2_size: u64) -> Result<Self> {
+        let mut root_down = proof.sibling_hashes.clone();
+        root_down.reverse();
+        for siblings in vec![root_down, proof.sibling_hashes.clone()] {
+            let root = merkle::get_root_with_drive(
+                address,
+                log2_size,
+                proof.target_hash,
+                &siblings,
+            )?;
+            if root == proof.root_hash {
+                return Ok(ContractProof {
+                    target_hash: proof.target_hash,
+                    siblings: siblings,
+                    root_hash: proof.root_hash,
+                });
+            }
+        }
+        Err(format!(
+            "Proof of {:x} with log2 size {} doesn't lead from {:x} to {:x}",
+            address, log2_size, proof.target_hash, proof.root_hash
+        )
+        .into())
+    }
+
+    /// Check the proof is of `target_hash` in the machine with `root_hash`
+    pub fn check(&self, target_hash: H256, root_hash: H256) -> Result<()> {
+        if self.target_hash != target_hash || self.root_hash != root_hash {
+            return Err(format!(
+                "Proof of {:x} in {:x} was expected of {:x} in {:x}",
+                self.target_hash, self.root_hash, target_hash, root_hash
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Siblings as the `bytes32[]` argument of the contract
+    pub fn to_token(&self) -> Token {
+        Token::Array(
+            self.siblings
+                .iter()
+                .map(|hash| Token::FixedBytes(hash.0.to_vec()))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::merkle::tests::{
+        get_machine_data, get_machine_root, get_word_siblings, word,
+    };
+    use super::*;
+
+    const ADDRESS: u64 = 8;
+
+    /// Proof of the word at `ADDRESS` of the fixture machine, with its
+    /// siblings listed the way the emulator lists them
+    fn get_proof() -> Proof {
+        let mut siblings = get_word_siblings();
+        siblings.reverse();
+        Proof {
+            target_hash: word(&get_machine_data()[8..16]),
+            sibling_hashes: siblings,
+            root_hash: get_machine_root(),
+        }
+    }
+
+    #[test]
+    fn lists_siblings_from_the_target_up() {
+        let proof = ContractProof::new(&get_proof(), ADDRESS, 3).unwrap();
+        assert_eq!(proof.siblings, get_word_siblings());
+        assert_eq!(proof.root_hash, get_machine_root());
+        assert_eq!(
+            merkle::get_root_with_drive(
+                ADDRESS,
+                3,
+                proof.target_hash,
+                &proof.siblings
+            )
+            .unwrap(),
+            get_machine_root()
+        );
+    }
+
+    #[test]
+    fn takes_siblings_already_from_the_target_up() {
+        let mut proof = get_proof();
+        proof.sibling_hashes.reverse();
+        let proof = ContractProof::new(&proof, ADDRESS, 3).unwrap();
+        assert_eq!(proof.siblings, get_word_siblings());
+    }
+
+    #[test]
+    fn rejects_proofs_not_leading_to_the_root() {
+        let mut proof = get_proof();
+        proof.root_hash = H256::repeat_byte(1);
+        assert!(ContractProof::new(&proof, ADDRESS, 3).is_err());
+
+        let mut proof = get_proof();
+        proof.target_hash = word(&[1]);
+        assert!(ContractProof::new(&proof, ADDRESS, 3).is_err());
+
+        // another word of the machine, or a range of another size
+        assert!(ContractProof::new(&get_proof(), 0, 3).is_err());
+        assert!(ContractProof::new(&get_proof(), ADDRESS, 4).is_err());
+    }
+
+    #[test]
+    fn checks_the_target_and_root() {
+        let target_hash = word(&get_machine_data()[8..16]);
+        let root_hash = get_machine_root();
+        let proof = ContractProof::new(&get_proof(), ADDRESS, 3).unwrap();
+        assert!(proof.check(target_hash, root_hash).is_ok());
+        assert!(proof.check(root_hash, root_hash).is_err());
+        assert!(proof.check(target_hash, target_hash).is_err());
+    }
+
+    #[test]
+    fn passes_siblings_as_bytes32() {
+        let proof = ContractProof::new(&get_proof(), ADDRESS, 3).unwrap();
+        match proof.to_token() {
+            Token::Array(tokens) => {
+                assert_eq!(tokens.len(), 61);
+                for (token, sibling) in tokens.iter().zip(get_word_siblings()) {
+                    assert_eq!(
+                        *token,
+                        Token::FixedBytes(sibling.0.to_vec())
+                    );
+                }
+            }
+            _ => panic!("siblings not passed as an array"),
+        }
+    }
+}
@@ -0,0 +1,527 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Offline replay of the reaction of the node to a single instance.
+//!
+//! `cartesi_compute replay --instance <file> [--drives <dir>]
+//! [--manager <host:port>] [--now <timestamp>]` reads a saved instance
+//! and decides the reaction to it the way the node does, with the clock
+//! set to `--now` (the current time by default), and prints the reaction
+//! the node would have emitted. Requests to the machine manager go to a
+//! local one; they and the drives read are logged at the info level
+//! (`RUST_LOG=cartesi_compute::replay=info`). Drives kept off-chain are
+//! read from local images, those missing counting as missing from ipfs.
+//! Nothing is sent to the chain, the logger or ipfs: a reaction that
+//! needs them fails, as does the verification game, which is only played
+//! through the dispatcher archive. The gas of a claim is estimated by the
+//! configured ethereum node, if any.
+
+use super::cartesi_compute::{
+    get_local_drive_image, CartesiCompute, CartesiComputeCtx,
+    CartesiComputeCtxParsed, Drive,
+};
+use super::clock::{Clock, LocalClock, ManualClock};
+use super::compute::{
+    SessionRunResponse, SessionRunResponseOneOf, SessionRunResult,
+    EMULATOR_METHOD_RUN, EMULATOR_SERVICE_NAME,
+};
+use super::config::ComputeConfig;
+use super::configuration::Concern;
+use super::dispatcher::{Archive, Reaction};
+use super::emulator::{ArchiveEmulator, EmulatorBackend, ServiceClient};
+use super::error::*;
+use super::ethereum_types::{Address, U256};
+use super::gas::{GasEstimator, NoGasEstimator, Web3GasEstimator};
+use super::grpc::marshall::Marshaller;
+use super::ipfs_service::{IPFS_METHOD_GET, IPFS_SERVICE_NAME};
+use super::node::Node;
+use super::{build_ipfs_get_key, build_machine_id, policy, serde_json};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const REPLAY_COMMAND: &'static str = "replay";
+
+/// The fields of a saved `state::Instance` the replay needs
+#[derive(Deserialize)]
+struct ReplayInstance {
+    index: U256,
+    concern: ReplayConcern,
+    /// Outputs of `getState`, as `CartesiComputeCtxParsed` parses them
+    json_data: String,
+}
+
+#[derive(Deserialize)]
+struct ReplayConcern {
+    contract_address: Address,
+    user_address: Address,
+}
+
+/// Replay the instance described by the command line arguments that
+/// follow the replay command, on a node set up with `config`, writing
+/// the outcome to `output`
+pub fn run(
+    config: ComputeConfig,
+    args: &[String],
+    output: &mut dyn Write,
+) -> Result<()> {
+    let mut instance_path = None;
+    let mut drives_dir = config.storage.drives_dir();
+    let mut manager = "127.0.0.1:50051".to_string();
+    let mut now = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or(Error::from(format!("Missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "--instance" => instance_path = Some(value()?),
+            "--drives" => drives_dir = value()?,
+            "--manager" => manager = value()?,
+            "--now" => {
+                let timestamp = value()?;
+                now = Some(timestamp.parse::<u64>().chain_err(|| {
+                    format!("Invalid timestamp {}", timestamp)
+                })?);
+            }
+            // read by the configuration loader
+            "--config_path" => {
+                value()?;
+            }
+            _ if arg.starts_with("--config_path=") => {}
+            _ => return Err(format!("Unknown replay option {}", arg).into()),
+        }
+    }
+    let instance_path = instance_path.ok_or(Error::from(
+        "The instance to replay is required (--instance)",
+    ))?;
+    let now = match now {
+        Some(now) => now,
+        None => LocalClock.now()?,
+    };
+
+    let json = std::fs::read_to_string(&instance_path)
+        .chain_err(|| format!("Could not read instance {}", instance_path))?;
+    let instance: ReplayInstance = serde_json::from_str(&json)
+        .chain_err(|| format!("Could not parse instance {}", instance_path))?;
+    let parsed: CartesiComputeCtxParsed =
+        serde_json::from_str(&instance.json_data).chain_err(|| {
+            format!(
+                "Could not parse cartesi compute instance json_data: {}",
+                &instance.json_data
+            )
+        })?;
+    let ctx = CartesiComputeCtx::try_from(parsed)?;
+    let concern = Concern {
+        contract_address: instance.concern.contract_address,
+        user_address: instance.concern.user_address,
+    };
+
+    let gas: Arc<dyn GasEstimator> = match &config.url {
+        Some(url) => Arc::new(Web3GasEstimator::new(url)?),
+        None => Arc::new(NoGasEstimator),
+    };
+    let node = Node::new(config, Arc::new(ManualClock::new(now)), gas);
+    let archive = RecordingArchive::new(&manager)?;
+    let emulator = ArchiveEmulator::new(&archive, &node);
+    replay(
+        &node,
+        &archive,
+        &emulator,
+        &drives_dir,
+        &concern,
+        instance.index,
+        ctx,
+        output,
+    )
+}
+
+/// Decide the reaction of `node` to the instance at `index` in state
+/// `ctx` and write it to `output`, ending the emulator session it used
+fn replay(
+    node: &Node,
+    archive: &dyn ServiceClient,
+    emulator: &dyn EmulatorBackend,
+    drives_dir: &str,
+    concern: &Concern,
+    index: U256,
+    ctx: CartesiComputeCtx,
+    output: &mut dyn Write,
+) -> Result<()> {
+    writeln!(
+        output,
+        "Instance {} in state {}, seen from {:x} at {}",
+        index,
+        ctx.current_state,
+        concern.user_address,
+        node.clock.now()?
+    )?;
+    let violations = policy::get_rejections(
+        &node.config.policy,
+        node.config.mode,
+        &ctx,
+        concern.user_address,
+    );
+    for violation in violations {
+        writeln!(output, "Policy violation: {}", violation)?;
+    }
+
+    let fetch_drive = |drive: &Drive, _ipfs_only: bool| {
+        get_replay_drive_image(node, drives_dir, drive)
+    };
+    let reaction = CartesiCompute::react_to_instance(
+        node,
+        archive,
+        emulator,
+        &fetch_drive,
+        concern,
+        index,
+        ctx,
+        None,
+    );
+
+    // leave nothing behind in the machine manager
+    let machine_id = build_machine_id(index, &concern.user_address);
+    if let Err(e) = node.sessions.end(emulator, &machine_id) {
+        writeln!(
+            output,
+            "Could not end emulator session {}: {}",
+            machine_id, e
+        )?;
+    }
+
+    match reaction? {
+        Reaction::Transaction(request) => writeln!(
+            output,
+            "Reaction: transaction {}({:?}), gas {:?}",
+            request.function, request.data, request.gas
+        )?,
+        _ => writeln!(output, "Reaction: idle")?,
+    }
+    Ok(())
+}
+
+/// Local image of a drive kept off-chain, as `ResponseInvalidError` when
+/// there is none, the way the node sees a drive missing from ipfs
+fn get_replay_drive_image(
+    node: &Node,
+    dir: &str,
+    drive: &Drive,
+) -> Result<String> {
+    let path = format!("{}/{:x}", dir, drive.root_hash);
+    if !Path::new(&path).exists() {
+        info!("Drive {:x} not found in {}", drive.root_hash, dir);
+        return Err(Error::from(ErrorKind::ResponseInvalidError(
+            IPFS_SERVICE_NAME.to_string(),
+            build_ipfs_get_key(drive.ipfs_path.clone()),
+            IPFS_METHOD_GET.to_string(),
+        )));
+    }
+    info!("Drive {:x} read from {}", drive.root_hash, path);
+    get_local_drive_image(node, dir, drive)
+}
+
+/// Direct connection to a machine manager, standing in for the archive
+/// and logging every request. Responses are kept by key, as the archive
+/// keeps them.
+struct RecordingArchive {
+    client: grpc::Client,
+    responses: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+/// Passes already encoded messages through untouched
+struct RawMarshaller;
+
+impl Marshaller<Vec<u8>> for RawMarshaller {
+    fn write(&self, message: &Vec<u8>) -> grpc::Result<Vec<u8>> {
+        Ok(message.clone())
+    }
+
+    fn read(&self, bytes: bytes::Bytes) -> grpc::Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl RecordingArchive {
+    fn new(address: &str) -> Result<Self> {
+        let invalid =
+            || Error::from(format!("Invalid manager address {}", address));
+        let mut parts = address.rsplitn(2, ':');
+        let port = parts
+            .next()
+            .and_then(|port| port.parse::<u16>().ok())
+            .ok_or_else(invalid)?;
+        let host = parts.next().ok_or_else(invalid)?;
+        let client = grpc::Client::new_plain(host, port, Default::default())
+            .map_err(|e| {
+                Error::from(format!(
+                    "Could not connect to machine manager {}: {:?}",
+                    address, e
+                ))
+            })?;
+        Ok(RecordingArchive {
+            client: client,
+            responses: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn call(&self, method: &str, request: Vec<u8>) -> Result<Vec<u8>> {
+        let descriptor = Arc::new(grpc::rt::MethodDescriptor {
+            name: method.to_string(),
+            streaming: grpc::rt::GrpcStreaming::Unary,
+            req_marshaller: Box::new(RawMarshaller),
+            resp_marshaller: Box::new(RawMarshaller),
+        });
+        self.client
+            .call_unary(grpc::RequestOptions::new(), request, descriptor)
+            .wait_drop_metadata()
+            .map_err(|e| {
+                Error::from(format!(
+                    "Machine manager request {} failed: {:?}",
+                    method, e
+                ))
+            })
+    }
+}
+
+impl ServiceClient for RecordingArchive {
+    fn get_response(
+        &self,
+        service: String,
+        key: String,
+        method: String,
+        request: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        info!("{} {} ({})", service, method, key);
+        if service != EMULATOR_SERVICE_NAME {
+            return Err(format!(
+                "Replay only reaches the emulator, not {} ({})",
+                service, key
+            )
+            .into());
+        }
+        if let Some(response) = self.responses.lock().unwrap().get(&key) {
+            info!("{} ({}) answered before", method, key);
+            return Ok(response.clone());
+        }
+        let response = self.call(&method, request)?;
+        info!(
+            "{} ({}) answered with {} bytes",
+            method,
+            key,
+            response.len()
+        );
+        self.responses.lock().unwrap().insert(key, response.clone());
+        Ok(response)
+    }
+
+    fn get_run_result(
+        &self,
+        key: String,
+        request: Vec<u8>,
+    ) -> Result<SessionRunResult> {
+        info!(
+            "{} {} ({})",
+            EMULATOR_SERVICE_NAME, EMULATOR_METHOD_RUN, key
+        );
+        loop {
+            let response: SessionRunResponse =
+                self.call(EMULATOR_METHOD_RUN, request.clone())?.into();
+            match response.one_of {
+                SessionRunResponseOneOf::RunProgress(p) => {
+                    info!("{} running, {}%", key, p.progress);
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+                SessionRunResponseOneOf::RunResult(r) => {
+                    for hash in &r.hashes {
+                        info!("{} hash {:x}", key, hash);
+                    }
+                    return Ok(r);
+                }
+            }
+        }
+    }
+
+    fn as_archive(&self) -> Option<&Archive> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::cartesi_compute::{PartyParsed, State};
+    use super::super::config::ConcernConfig;
+    use super::super::emulator::MockEmulator;
+    use super::super::ethereum_types::H256;
+    use super::super::gas::FixedGasEstimator;
+    use super::*;
+
+    const USER: u64 = 2;
+    const DRIVE_POSITION: u64 = 1 << 32;
+    const OUTPUT_POSITION: u64 = 1 << 33;
+    const LOG2_SIZE: u64 = 5;
+    const FINAL_TIME: u64 = 1000;
+    const NOW: u64 = 100;
+    const DEADLINE: u64 = 10_000;
+
+    /// Services a replay of an instance with its drives on-chain never
+    /// reaches
+    struct NoServices;
+
+    impl ServiceClient for NoServices {
+        fn get_response(
+            &self,
+            service: String,
+            key: String,
+            _method: String,
+            _request: Vec<u8>,
+        ) -> Result<Vec<u8>> {
+            Err(format!("Unexpected {} request {}", service, key).into())
+        }
+
+        fn get_run_result(
+            &self,
+            key: String,
+            _request: Vec<u8>,
+        ) -> Result<SessionRunResult> {
+            Err(format!("Unexpected run request {}", key).into())
+        }
+
+        fn as_archive(&self) -> Option<&Archive> {
+            None
+        }
+    }
+
+    fn get_node() -> Node {
+        let mut config = ComputeConfig::default();
+        config.storage.results_path = std::env::temp_dir()
+            .join(format!("cartesi-compute-replay-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        std::fs::create_dir_all(&config.storage.results_path).unwrap();
+        config.main_concern = Some(ConcernConfig {
+            abi: Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../deployments/goerli/CartesiCompute.json")
+                .to_string_lossy()
+                .into_owned(),
+        });
+        Node::new(
+            config,
+            Arc::new(ManualClock::new(NOW)),
+            Arc::new(FixedGasEstimator::new(100_000)),
+        )
+    }
+
+    fn get_drive() -> Drive {
+        Drive {
+            position: DRIVE_POSITION.into(),
+            log2_size: LOG2_SIZE.into(),
+            direct_value: b"input".to_vec(),
+            ipfs_path: String::new(),
+            root_hash: H256::zero(),
+            provider: Address::zero(),
+            waits_provider: false,
+            needs_logger: false,
+            download_as_car: false,
+        }
+    }
+
+    /// Instance waiting for the claim of our address
+    fn get_ctx(initial_hash: H256) -> CartesiComputeCtx {
+        CartesiComputeCtx {
+            template_hash: MockEmulator::get_template_hash(),
+            initial_hash: initial_hash,
+            claimed_final_hash: H256::zero(),
+            claimed_output: vec![],
+            claimer: Address::from_low_u64_be(USER),
+            challenger: Address::zero(),
+            deadline: DEADLINE.into(),
+            output_position: OUTPUT_POSITION.into(),
+            output_log2_size: LOG2_SIZE.into(),
+            final_time: FINAL_TIME.into(),
+            current_state: State::WaitingClaim,
+            input_drives: vec![get_drive()],
+            partyState: PartyParsed {
+                is_party: true,
+                has_voted: false,
+                has_cheated: false,
+                array_idx: U256::zero(),
+            }
+            .into(),
+            noChallengeDrive: false,
+        }
+    }
+
+    #[test]
+    fn replays_a_claim() {
+        let node = get_node();
+        let emulator =
+            MockEmulator::new().with_output(OUTPUT_POSITION, b"out".to_vec());
+        emulator
+            .new_session("hashes", MockEmulator::get_template_hash())
+            .unwrap();
+        emulator
+            .write("hashes", 0, DRIVE_POSITION, LOG2_SIZE, b"input".to_vec())
+            .unwrap();
+        let initial_hash = emulator.run("hashes", vec![0]).unwrap()[0];
+        emulator.end_session("hashes").unwrap();
+        let concern = Concern {
+            contract_address: Address::from_low_u64_be(1),
+            user_address: Address::from_low_u64_be(USER),
+        };
+
+        let mut output = vec![];
+        replay(
+            &node,
+            &NoServices,
+            &emulator,
+            "",
+            &concern,
+            7.into(),
+            get_ctx(initial_hash),
+            &mut output,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            format!(
+                "Instance 7 in state WaitingClaim, seen from {:x} at {}",
+                concern.user_address, NOW
+            )
+        );
+        assert!(lines[1].starts_with("Reaction: transaction submitClaim("));
+        // the session is not left behind
+        assert!(emulator.get_session_ids().is_empty());
+    }
+}
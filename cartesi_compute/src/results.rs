@@ -0,0 +1,146 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Local record of the results this node verified, one json file per
+//! instance, contract and user in the results directory, for consumers
+//! that can't wait for the instance to finish on-chain.
+
+use super::build_instance_file_name;
+use super::config::StorageConfig;
+use super::configuration::Concern;
+use super::error::*;
+use super::ethereum_types::{H256, U256};
+use super::hex;
+use super::serde_json;
+
+use std::path::Path;
+
+/// Outcome of checking a claim against our own machine
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Verification {
+    pub index: U256,
+    pub final_hash: H256,
+    pub claimed_final_hash: H256,
+    /// Output read from the machine, hex encoded
+    pub output: String,
+    /// Output submitted by the claimer, hex encoded
+    pub claimed_output: String,
+    pub final_hash_matches: bool,
+    /// The contract only takes claims of the full output range, so a
+    /// claim of any other length doesn't match
+    pub output_matches: bool,
+}
+
+impl Verification {
+    pub fn new(
+        index: U256,
+        final_hash: H256,
+        claimed_final_hash: H256,
+        output: Vec<u8>,
+        claimed_output: Vec<u8>,
+    ) -> Self {
+        Verification {
+            index: index,
+            final_hash: final_hash,
+            claimed_final_hash: claimed_final_hash,
+            output_matches: claimed_output == output,
+            output: hex::encode(&output),
+            claimed_output: hex::encode(&claimed_output),
+            final_hash_matches: final_hash == claimed_final_hash,
+        }
+    }
+
+    /// What the verification says of the claim
+    pub fn verdict(&self) -> Verdict {
+        if !self.final_hash_matches {
+            Verdict::WrongFinalHash
+        } else if !self.output_matches {
+            Verdict::WrongOutput
+        } else {
+            Verdict::Correct
+        }
+    }
+
+    /// Whether this is the verification of the claim of
+    /// `claimed_final_hash` and `claimed_output`
+    pub fn is_of_claim(
+        &self,
+        claimed_final_hash: H256,
+        claimed_output: &[u8],
+    ) -> bool {
+        self.claimed_final_hash == claimed_final_hash
+            && self.claimed_output == hex::encode(claimed_output)
+    }
+}
+
+/// Whether a claim holds against our own machine
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Correct,
+    /// The claimed final hash is not the one of our machine
+    WrongFinalHash,
+    /// The final hash matches, the claimed output doesn't
+    WrongOutput,
+}
+
+fn get_result_path(
+    storage: &StorageConfig,
+    concern: &Concern,
+    index: U256,
+) -> String {
+    format!(
+        "{}/{}.json",
+        storage.results_dir(),
+        build_instance_file_name(concern, index)
+    )
+}
+
+/// Record the verification of an instance, replacing the previous one
+pub fn store(
+    storage: &StorageConfig,
+    concern: &Concern,
+    verification: &Verification,
+) -> Result<()> {
+    let path = get_result_path(storage, concern, verification.index);
+    let json = serde_json::to_vec_pretty(verification)
+        .chain_err(|| "Could not serialize verification result")?;
+    std::fs::write(&path, json)
+        .chain_err(|| format!("Could not write verification result {}", path))
+}
+
+/// The verification recorded for an instance, if any
+pub fn load(
+    storage: &StorageConfig,
+    concern: &Concern,
+    index: U256,
+) -> Result<Option<Verification>> {
+    let path = get_result_path(storage, concern, index);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let json = std::fs::read(&path)
+        .chain_err(|| format!("Could not read verification result {}", path))?;
+    serde_json::from_slice(&json)
+        .map(Some)
+        .chain_err(|| format!("Could not parse verification result {}", path))
+}
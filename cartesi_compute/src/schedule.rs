@@ -0,0 +1,264 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Time left before deadlines and the order machine work is done in.
+//!
+//! Every state of an instance ends at its deadline. The node estimates
+//! how long the work still due in the state takes, from the drives left
+//! to download and the cycles to run, and warns when that and the
+//! configured safety margin don't fit in the time left. When more
+//! instances want the machine than `max_concurrent_runs`, the ones with
+//! the nearest deadlines go first.
+
+use super::cartesi_compute::CartesiComputeCtx;
+use super::clock::Clock;
+use super::config::{ComputeConfig, ScheduleConfig};
+use super::error::*;
+use super::ethereum_types::{Address, U256};
+use super::policy::get_drive_size;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Instances not asking for the machine for this long gave up on it
+const STALE_AFTER: Duration = Duration::from_secs(600);
+
+/// Seconds the work left for the instance is expected to take, seen
+/// from `user_address`, running the machine if `runs_machine`
+pub fn estimate_work(
+    config: &ComputeConfig,
+    ctx: &CartesiComputeCtx,
+    user_address: Address,
+    runs_machine: bool,
+) -> u64 {
+    let schedule = &config.schedule;
+    let storage = &config.storage;
+
+    let mut download: u64 = 0;
+    for drive in &ctx.input_drives {
+        let downloaded = Path::new(
+            &storage.drive_path(&format!("{:x}", drive.root_hash)),
+        )
+        .exists();
+        if drive.needs_logger && drive.provider != user_address && !downloaded
+        {
+            download = download.saturating_add(get_drive_size(drive.log2_size));
+        }
+    }
+
+    let mut work = download / schedule.download_rate;
+    if runs_machine {
+        let cycles = if ctx.final_time > U256::from(u64::max_value()) {
+            u64::max_value()
+        } else {
+            ctx.final_time.as_u64()
+        };
+        work = work.saturating_add(cycles / schedule.cycles_per_second);
+    }
+    work
+}
+
+/// Seconds left before the deadline of the current state
+pub fn get_time_left(
+    clock: &dyn Clock,
+    ctx: &CartesiComputeCtx,
+) -> Result<u64> {
+    let deadline = ctx.deadline.as_u64();
+    Ok(deadline.saturating_sub(clock.now()?))
+}
+
+/// Warn when `work` seconds and the safety margin don't fit in the time
+/// left in the state, returning the time left
+pub fn check_time_left(
+    schedule: &ScheduleConfig,
+    clock: &dyn Clock,
+    index: U256,
+    ctx: &CartesiComputeCtx,
+    work: u64,
+) -> Result<u64> {
+    let time_left = get_time_left(clock, ctx)?;
+    let margin = schedule.safety_margin;
+    if work.saturating_add(margin) > time_left {
+        warn!(
+            "Cartesi Compute (index {}) has {}s left in {}, expected work takes {}s with a {}s margin",
+            index, time_left, ctx.current_state, work, margin
+        );
+    }
+    Ok(time_left)
+}
+
+/// Machines asking for work, letting at most `max_concurrent_runs` of
+/// them run at a time
+pub struct Scheduler {
+    /// Machines waiting for or doing work, by machine id
+    machines: Mutex<HashMap<String, Machine>>,
+    max_concurrent_runs: usize,
+}
+
+struct Machine {
+    /// Deadline of the state the work is due in
+    deadline: u64,
+    /// When the machine last asked for work
+    seen: Instant,
+    /// Whether the machine was admitted and is doing work
+    running: bool,
+}
+
+impl Scheduler {
+    pub fn new(max_concurrent_runs: usize) -> Self {
+        Scheduler {
+            machines: Mutex::new(HashMap::new()),
+            max_concurrent_runs: max_concurrent_runs,
+        }
+    }
+
+    /// Admit the machine `machine_id` to run now, for a state ending at
+    /// `deadline`, if it is running already or there is room for it once
+    /// the waiting machines with nearer deadlines run
+    pub fn admit(&self, machine_id: &str, deadline: u64) -> Option<Admission> {
+        let now = Instant::now();
+        let mut machines = self.machines.lock().unwrap();
+        machines.retain(|_, machine| {
+            now.duration_since(machine.seen) < STALE_AFTER
+        });
+
+        let running = {
+            let machine =
+                machines.entry(machine_id.to_string()).or_insert(Machine {
+                    deadline: deadline,
+                    seen: now,
+                    running: false,
+                });
+            machine.deadline = deadline;
+            machine.seen = now;
+            machine.running
+        };
+
+        if !running {
+            let runs = machines.values().filter(|m| m.running).count();
+            let ahead = machines
+                .iter()
+                .filter(|&(id, m)| {
+                    !m.running
+                        && (m.deadline, id.as_str()) < (deadline, machine_id)
+                })
+                .count();
+            if runs + ahead >= self.max_concurrent_runs {
+                return None;
+            }
+            machines.get_mut(machine_id).unwrap().running = true;
+        }
+
+        Some(Admission {
+            scheduler: self,
+            machine_id: machine_id.to_string(),
+            kept: false,
+        })
+    }
+
+    /// The machine `machine_id` is done with the work it was admitted
+    /// for, or gave up on it
+    pub fn release(&self, machine_id: &str) {
+        self.machines.lock().unwrap().remove(machine_id);
+    }
+}
+
+/// A machine admitted to run, released when dropped unless kept
+pub struct Admission<'a> {
+    scheduler: &'a Scheduler,
+    machine_id: String,
+    kept: bool,
+}
+
+impl<'a> Admission<'a> {
+    /// Release the machine once its work is over, keeping it running
+    /// while a service is still doing the work of `result`
+    pub fn finish<T>(mut self, result: &Result<T>) {
+        if let Err(e) = result {
+            if let ErrorKind::ServiceNeedsRetry(..) = e.kind() {
+                self.kept = true;
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Admission<'a> {
+    fn drop(&mut self) {
+        if !self.kept {
+            self.scheduler.release(&self.machine_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admits_up_to_the_limit_of_runs() {
+        let scheduler = Scheduler::new(2);
+        let first = scheduler.admit("a", 10).unwrap();
+        let _second = scheduler.admit("b", 20).unwrap();
+        assert!(scheduler.admit("c", 5).is_none());
+
+        drop(first);
+        assert!(scheduler.admit("c", 5).is_some());
+    }
+
+    #[test]
+    fn nearer_deadlines_go_first() {
+        let scheduler = Scheduler::new(1);
+        let running = scheduler.admit("a", 10).unwrap();
+        assert!(scheduler.admit("early", 20).is_none());
+        assert!(scheduler.admit("late", 30).is_none());
+
+        // the free run is left for the nearest waiting deadline
+        drop(running);
+        assert!(scheduler.admit("late", 30).is_none());
+        assert!(scheduler.admit("early", 20).is_some());
+    }
+
+    #[test]
+    fn keeps_runs_a_service_is_still_doing() {
+        let scheduler = Scheduler::new(1);
+        let retry: Result<()> = Err(Error::from(ErrorKind::ServiceNeedsRetry(
+            "emulator".into(),
+            "key".into(),
+            "method".into(),
+            vec![],
+            "CartesiCompute".into(),
+            1,
+            0,
+            "running".into(),
+        )));
+        scheduler.admit("a", 10).unwrap().finish(&retry);
+        assert!(scheduler.admit("b", 5).is_none());
+        assert!(scheduler.admit("a", 10).is_some());
+
+        let failure: Result<()> = Err(Error::from("failed"));
+        scheduler.admit("a", 10).unwrap().finish(&failure);
+        assert!(scheduler.admit("b", 5).is_some());
+    }
+}
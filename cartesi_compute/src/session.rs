@@ -0,0 +1,226 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Bookkeeping of the emulator sessions kept alive across reactions.
+//!
+//! A session is prepared once per instance: created from the template and
+//! with every input drive mounted. The manager remembers which sessions
+//! are prepared, keyed by the inputs that determine their initial hash, so
+//! later reactions go straight to running it. Sessions are only ended
+//! when their instance reaches a terminal state or when they are evicted
+//! to make room for another one.
+//!
+//! A prepared session is reused without asking the emulator whether it
+//! still holds it. If the emulator answers a request on it as invalid,
+//! e.g. after it restarted, the session is taken as lost and rebuilt.
+//!
+//! Archive keys are derived from the session id, so a session recreated
+//! after an eviction or a loss gets a new id, otherwise the archive would
+//! answer its requests with the responses given to the previous one.
+//!
+//! The table is kept in memory only. A restarted node rebuilds every
+//! session, forcing out the one left in the emulator; ids start over
+//! then, which is safe since the archive does not outlive the node.
+
+use super::emulator::{EmulatorBackend, Proof};
+use super::error::*;
+use super::ethereum_types::H256;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A live emulator session
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub session_id: String,
+    /// Hash of the template and drives the session is built from,
+    /// which determine its initial hash
+    pub inputs: H256,
+    /// Proof of each input drive, taken right after mounting it;
+    /// `None` until the session is fully prepared
+    pub drives: Option<Vec<Proof>>,
+    last_used: u64,
+}
+
+/// The live sessions, keeping at most `max_sessions` of them
+pub struct SessionManager {
+    table: Mutex<SessionTable>,
+    max_sessions: usize,
+}
+
+#[derive(Default)]
+struct SessionTable {
+    /// Sessions by machine id
+    sessions: HashMap<String, Session>,
+    /// Number of sessions already ended for each machine id
+    generations: HashMap<String, u64>,
+    clock: u64,
+}
+
+impl SessionTable {
+    fn session_id(&self, machine_id: &str) -> String {
+        match self.generations.get(machine_id) {
+            Some(generation) if *generation > 0 => {
+                format!("{}:{}", machine_id, generation)
+            }
+            _ => machine_id.to_string(),
+        }
+    }
+
+    fn touch(&mut self, machine_id: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(session) = self.sessions.get_mut(machine_id) {
+            session.last_used = clock;
+        }
+    }
+
+    /// The session was ended, the next one gets a new id
+    fn forget(&mut self, machine_id: &str) {
+        if self.sessions.remove(machine_id).is_some() {
+            *self.generations.entry(machine_id.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    /// Least recently used session other than `machine_id`
+    fn least_recently_used(&self, machine_id: &str) -> Option<String> {
+        self.sessions
+            .iter()
+            .filter(|(id, _)| id.as_str() != machine_id)
+            .min_by_key(|(_, session)| session.last_used)
+            .map(|(id, _)| id.clone())
+    }
+}
+
+impl SessionManager {
+    pub fn new(max_sessions: usize) -> Self {
+        SessionManager {
+            table: Mutex::new(SessionTable::default()),
+            max_sessions: max_sessions,
+        }
+    }
+
+    /// The prepared session of `machine_id`, if it was built from `inputs`
+    pub fn get_prepared(
+        &self,
+        machine_id: &str,
+        inputs: H256,
+    ) -> Option<Session> {
+        let mut table = self.table.lock().unwrap();
+        table.touch(machine_id);
+        match table.sessions.get(machine_id) {
+            Some(session)
+                if session.inputs == inputs && session.drives.is_some() =>
+            {
+                Some(session.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Start preparing a session for `machine_id` built from `inputs`,
+    /// returning its id. Ends the session previously built from other
+    /// inputs, and evicts the least recently used sessions to stay within
+    /// `max_sessions`.
+    pub fn begin(
+        &self,
+        emulator: &dyn EmulatorBackend,
+        machine_id: &str,
+        inputs: H256,
+    ) -> Result<String> {
+        let stale = match self.table.lock().unwrap().sessions.get(machine_id)
+        {
+            Some(session) => session.inputs != inputs,
+            None => false,
+        };
+        if stale {
+            warn!("Inputs of session {} changed, rebuilding it", machine_id);
+            self.end(emulator, machine_id)?;
+        }
+
+        loop {
+            let evicted = {
+                let table = self.table.lock().unwrap();
+                if table.sessions.contains_key(machine_id)
+                    || table.sessions.len() < self.max_sessions
+                {
+                    None
+                } else {
+                    table.least_recently_used(machine_id)
+                }
+            };
+            match evicted {
+                Some(evicted) => {
+                    info!("Evicting emulator session {}", evicted);
+                    self.end(emulator, &evicted)?;
+                }
+                None => break,
+            }
+        }
+
+        let mut table = self.table.lock().unwrap();
+        let session_id = table.session_id(machine_id);
+        table.clock += 1;
+        let clock = table.clock;
+        table
+            .sessions
+            .entry(machine_id.to_string())
+            .or_insert(Session {
+                session_id: session_id.clone(),
+                inputs: inputs,
+                drives: None,
+                last_used: clock,
+            });
+        Ok(session_id)
+    }
+
+    /// Record that every drive of the session of `machine_id` is mounted
+    pub fn finish(&self, machine_id: &str, drives: Vec<Proof>) {
+        if let Some(session) =
+            self.table.lock().unwrap().sessions.get_mut(machine_id)
+        {
+            session.drives = Some(drives);
+        }
+    }
+
+    /// Drop the session of `machine_id` the emulator no longer holds
+    pub fn lost(&self, machine_id: &str) {
+        self.table.lock().unwrap().forget(machine_id);
+    }
+
+    /// Id of the current session of `machine_id`, live or not
+    pub fn session_id(&self, machine_id: &str) -> String {
+        self.table.lock().unwrap().session_id(machine_id)
+    }
+
+    /// End the session of `machine_id` in the emulator
+    pub fn end(
+        &self,
+        emulator: &dyn EmulatorBackend,
+        machine_id: &str,
+    ) -> Result<()> {
+        emulator.end_session(&self.session_id(machine_id))?;
+        self.table.lock().unwrap().forget(machine_id);
+        Ok(())
+    }
+}
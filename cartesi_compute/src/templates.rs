@@ -0,0 +1,584 @@
+// Copyright (C) 2020 Cartesi Pte. Ltd.
+
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later
+// version.
+
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+// details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Note: This component currently has dependencies that are licensed under the
+// GNU GPL, version 3, and so you should treat this component as a whole as
+// being under the GPL version 3. But all Cartesi-written code in this component
+// is licensed under the Apache License, version 2, or a compatible permissive
+// license, and can be used independently under the Apache v2 license. After
+// this component is rewritten, the entire component will be released under the
+// Apache v2 license.
+
+//! Resolution of machine templates by their hash.
+//!
+//! Templates are stored machines, one directory per template hash in the
+//! templates directory. Missing templates are fetched from the location
+//! the template registry gives for their hash, either a tar archive in
+//! IPFS or a local directory or tar archive, and only kept once the
+//! machine manager loads them with the template hash as root hash.
+//! Archives hold the files of the stored machine, either at their root
+//! or in a single top-level directory, which is stripped.
+//! Templates already stored are checked the same way before their first
+//! session.
+
+use super::config::{StorageConfig, TemplatesConfig};
+use super::emulator::{self, ServiceClient};
+use super::error::*;
+use super::ethereum_types::H256;
+use super::ipfs_service::{
+    GetFileRequest, GetFileResponse, GetFileResponseOneOf, IPFS_METHOD_GET,
+    IPFS_SERVICE_NAME,
+};
+use super::node::Node;
+use super::serde_yaml;
+use super::tar::{self, EntryType};
+use super::{build_ipfs_get_key, hex};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+/// Log2 size of the template archives with none given in the registry
+const DEFAULT_ARCHIVE_LOG2_SIZE: u32 = 30;
+
+/// Where to get a template from, as listed in the registry
+#[derive(Debug, Clone, Deserialize)]
+pub struct TemplateLocation {
+    /// Tar archive of the stored machine in IPFS
+    pub ipfs_path: Option<String>,
+    /// Upper bound on the log2 size of the archive and of what it holds
+    pub log2_size: Option<u32>,
+    /// Stored machine directory, or tar archive of it, on this node
+    pub path: Option<String>,
+}
+
+/// Availability of a template, as shown in the pretty instance
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum TemplateStatus {
+    Available,
+    /// Stored, but not loaded by the machine manager yet
+    Stored,
+    /// Not stored yet, but listed in the registry
+    Registered,
+    Missing,
+    Invalid(String),
+}
+
+/// Load the template registry, mapping template hashes to locations
+pub fn load_registry(
+    templates: &TemplatesConfig,
+) -> Result<HashMap<H256, TemplateLocation>> {
+    let path = match &templates.registry_path {
+        Some(path) => path,
+        None => return Ok(HashMap::new()),
+    };
+    let file = std::fs::File::open(&path)
+        .chain_err(|| format!("Could not open template registry {}", path))?;
+    let entries: HashMap<String, TemplateLocation> =
+        serde_yaml::from_reader(file).chain_err(|| {
+            format!("Could not parse template registry {}", path)
+        })?;
+
+    let mut registry = HashMap::new();
+    for (hash, location) in entries {
+        let bytes = hex::decode(hash.trim_start_matches("0x"))
+            .ok()
+            .filter(|bytes| bytes.len() == H256::len_bytes())
+            .ok_or(Error::from(format!(
+                "Invalid template hash {} in registry {}",
+                hash, path
+            )))?;
+        if location.ipfs_path.is_some() == location.path.is_some() {
+            return Err(format!(
+                "Template {} in registry {} needs either ipfs_path or path",
+                hash, path
+            )
+            .into());
+        }
+        registry.insert(H256::from_slice(&bytes), location);
+    }
+    Ok(registry)
+}
+
+/// Templates the machine manager loaded with their hash as root hash
+#[derive(Default)]
+pub struct VerifiedTemplates {
+    hashes: Mutex<HashSet<H256>>,
+}
+
+impl VerifiedTemplates {
+    pub fn contains(&self, template_hash: H256) -> bool {
+        self.hashes.lock().unwrap().contains(&template_hash)
+    }
+
+    fn insert(&self, template_hash: H256) {
+        self.hashes.lock().unwrap().insert(template_hash);
+    }
+}
+
+/// Availability of a template, without fetching or loading it
+pub fn get_status(node: &Node, template_hash: H256) -> TemplateStatus {
+    let config = &node.config;
+    let template_path = config.storage.template_path(template_hash);
+    if Path::new(&template_path).is_dir() {
+        if node.verified_templates.contains(template_hash) {
+            return TemplateStatus::Available;
+        }
+        return TemplateStatus::Stored;
+    }
+    match load_registry(&config.templates) {
+        Ok(ref registry) if registry.contains_key(&template_hash) => {
+            TemplateStatus::Registered
+        }
+        Ok(_) => TemplateStatus::Missing,
+        Err(e) => TemplateStatus::Invalid(e.to_string()),
+    }
+}
+
+/// Make sure the template is stored and valid, fetching it if needed,
+/// and return its directory
+pub fn resolve(
+    archive: &dyn ServiceClient,
+    node: &Node,
+    template_hash: H256,
+) -> Result<String> {
+    let config = &node.config;
+    let storage = &config.storage;
+    let template_path = storage.template_path(template_hash);
+    if Path::new(&template_path).is_dir() {
+        if !node.verified_templates.contains(template_hash) {
+            check_template(archive, &template_path, template_hash)?;
+            node.verified_templates.insert(template_hash);
+        }
+        return Ok(template_path);
+    }
+
+    // a fetched template waits next to the final directory until it is
+    // checked, so it is never taken for a stored one
+    let unverified_path = format!("{}.unverified", template_path);
+    if !Path::new(&unverified_path).is_dir() {
+        let location = load_registry(&config.templates)?
+            .remove(&template_hash)
+            .ok_or(Error::from(format!(
+                "Template {:x} is neither stored in {} nor in the registry",
+                template_hash,
+                storage.templates_dir()
+            )))?;
+        fetch_template(archive, storage, location, template_hash)?;
+    }
+
+    if let Err(e) = check_template(archive, &unverified_path, template_hash) {
+        if let ErrorKind::ServiceNeedsRetry(..) = e.kind() {
+            return Err(e);
+        }
+        std::fs::remove_dir_all(&unverified_path)?;
+        return Err(e);
+    }
+    std::fs::rename(&unverified_path, &template_path)?;
+    node.verified_templates.insert(template_hash);
+    info!("Stored template {:x} in {}", template_hash, template_path);
+    Ok(template_path)
+}
+
+/// Get the template from `location` into its unverified directory
+fn fetch_template(
+    archive: &dyn ServiceClient,
+    storage: &StorageConfig,
+    location: TemplateLocation,
+    template_hash: H256,
+) -> Result<()> {
+    let template_path = storage.template_path(template_hash);
+    // unpack next to the unverified directory, so a partial template is
+    // never checked
+    let unpacked_path = format!("{}.unpacked", template_path);
+    if Path::new(&unpacked_path).exists() {
+        std::fs::remove_dir_all(&unpacked_path)?;
+    }
+
+    let log2_size = location.log2_size.unwrap_or(DEFAULT_ARCHIVE_LOG2_SIZE);
+    if let Some(ipfs_path) = location.ipfs_path {
+        let tar_path = fetch_ipfs_archive(
+            archive,
+            storage,
+            ipfs_path,
+            log2_size,
+            template_hash,
+        )?;
+        unpack_tar_file(&tar_path, &unpacked_path, 1 << log2_size)?;
+        std::fs::remove_file(&tar_path)?;
+    } else if let Some(path) = location.path {
+        if Path::new(&path).is_dir() {
+            copy_dir(&path, &unpacked_path)?;
+        } else {
+            unpack_tar_file(&path, &unpacked_path, 1 << log2_size)?;
+        }
+    }
+
+    std::fs::rename(&unpacked_path, format!("{}.unverified", template_path))?;
+    Ok(())
+}
+
+/// Check the machine manager loads the stored machine at `path` with
+/// `template_hash` as its root hash
+fn check_template(
+    archive: &dyn ServiceClient,
+    path: &str,
+    template_hash: H256,
+) -> Result<()> {
+    // a session of its own for each copy of the machine, so a replaced
+    // copy is never answered from the archive
+    let modified = std::fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .chain_err(|| format!("Template {} modified before the epoch", path))?;
+    let session_id = format!(
+        "template_{:x}_{}",
+        template_hash,
+        modified.as_nanos()
+    );
+    let root_hash =
+        emulator::get_stored_machine_hash(archive, &session_id, path)?;
+    if root_hash != template_hash {
+        return Err(format!(
+            "Template {} has root hash {:x}, expected {:x}",
+            path, root_hash, template_hash
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Get the tar archive of a template from IPFS into the drives
+/// directory, shared with the ipfs service
+fn fetch_ipfs_archive(
+    archive: &dyn ServiceClient,
+    storage: &StorageConfig,
+    ipfs_path: String,
+    log2_size: u32,
+    template_hash: H256,
+) -> Result<String> {
+    let key = build_ipfs_get_key(ipfs_path.clone());
+    let request = GetFileRequest {
+        ipfs_path,
+        log2_size,
+        output_path: storage
+            .drive_path(&format!("{:x}.template.tar", template_hash)),
+        // TODO: come up with better timeout
+        timeout: 600,
+    };
+
+    let data = archive.get_response(
+        IPFS_SERVICE_NAME.into(),
+        key.clone(),
+        IPFS_METHOD_GET.into(),
+        request.clone().into(),
+    )?;
+    let response = GetFileResponse::try_from(data)
+        .chain_err(|| format!("Malformed ipfs response for {}", key))?;
+    info!("Response received from Ipfs {:?}", response);
+
+    match response.one_of {
+        GetFileResponseOneOf::GetProgress(p) => {
+            Err(Error::from(ErrorKind::ServiceNeedsRetry(
+                IPFS_SERVICE_NAME.to_string(),
+                key,
+                IPFS_METHOD_GET.into(),
+                request.into(),
+                "CartesiCompute".into(),
+                1,
+                p.progress,
+                "IPFS still getting".to_string(),
+            )))
+        }
+        GetFileResponseOneOf::GetResult(r) => Ok(r.output_path),
+    }
+}
+
+fn copy_dir(from: &str, to: &str) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = Path::new(to).join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(
+                &entry.path().to_string_lossy(),
+                &target.to_string_lossy(),
+            )?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Unpack the regular files and directories of a tar archive,
+/// rejecting entries that would land outside `output_path` or hold more
+/// than `max_size` bytes in all
+fn unpack_tar_file(
+    tar_path: &str,
+    output_path: &str,
+    max_size: u64,
+) -> Result<()> {
+    let file = File::open(tar_path).chain_err(|| {
+        format!("Could not read template archive {}", tar_path)
+    })?;
+    unpack_tar(&mut BufReader::new(file), output_path, max_size)
+        .chain_err(|| format!("Could not unpack template archive {}", tar_path))
+}
+
+fn unpack_tar(
+    reader: &mut dyn Read,
+    output_path: &str,
+    max_size: u64,
+) -> Result<()> {
+    std::fs::create_dir_all(output_path)?;
+
+    let mut archive = tar::Archive::new(reader);
+    let mut total_size: u64 = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        let size = entry.header().size()?;
+        total_size = total_size.saturating_add(size);
+        if total_size > max_size {
+            return Err(format!(
+                "Entry {} of {} bytes takes the archive over {} bytes",
+                name.display(),
+                size,
+                max_size
+            )
+            .into());
+        }
+
+        // only plain names, so nothing lands outside the output
+        let mut target = Path::new(output_path).to_path_buf();
+        for component in name.components() {
+            match component {
+                Component::Normal(part) => target.push(part),
+                Component::CurDir => {}
+                _ => {
+                    return Err(
+                        format!("Unsafe entry {}", name.display()).into()
+                    )
+                }
+            }
+        }
+
+        match entry.header().entry_type() {
+            EntryType::Regular => {
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut file = File::create(&target)?;
+                let copied = io::copy(&mut entry, &mut file)?;
+                if copied != size {
+                    return Err(
+                        format!("Truncated entry {}", name.display()).into()
+                    );
+                }
+            }
+            EntryType::Directory if size == 0 => {
+                std::fs::create_dir_all(&target)?
+            }
+            kind => {
+                return Err(format!(
+                    "Unsupported entry {} of type {:?}",
+                    name.display(),
+                    kind
+                )
+                .into())
+            }
+        }
+    }
+
+    strip_root_dir(output_path)
+}
+
+/// Move the content of a single top-level directory of `path` up to
+/// `path`, as archives made from the machine directory have one
+fn strip_root_dir(path: &str) -> Result<()> {
+    let entries = std::fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    if entries.len() != 1 || !entries[0].file_type()?.is_dir() {
+        return Ok(());
+    }
+
+    let root = format!("{}.root", path);
+    std::fs::rename(entries[0].path(), &root)?;
+    std::fs::remove_dir(path)?;
+    std::fs::rename(&root, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 512;
+
+    /// Tar header and content of an entry, with `name` written as is
+    fn get_entry(
+        name: &str,
+        kind: EntryType,
+        size: u64,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut header = tar::Header::new_ustar();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_mode(0o644);
+        header.set_size(size);
+        header.set_entry_type(kind);
+        header.set_cksum();
+
+        let mut entry = header.as_bytes().to_vec();
+        entry.extend_from_slice(data);
+        let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        entry.resize(entry.len() + padding, 0);
+        entry
+    }
+
+    fn get_file(name: &str, data: &[u8]) -> Vec<u8> {
+        get_entry(name, EntryType::Regular, data.len() as u64, data)
+    }
+
+    /// Archive of `entries`, with its end of archive blocks
+    fn get_archive(entries: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut tar: Vec<u8> = entries.into_iter().flatten().collect();
+        tar.extend(vec![0u8; 2 * BLOCK_SIZE]);
+        tar
+    }
+
+    fn get_output_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "cartesi-compute-tar-{}-{}",
+            name,
+            std::process::id()
+        ));
+        if path.exists() {
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+        path.to_string_lossy().into_owned()
+    }
+
+    fn unpack(name: &str, tar: Vec<u8>, max_size: u64) -> Result<String> {
+        let output_path = get_output_path(name);
+        unpack_tar(&mut &tar[..], &output_path, max_size)?;
+        Ok(output_path)
+    }
+
+    #[test]
+    fn unpacks_files_and_directories() {
+        let tar = get_archive(vec![
+            get_file("config", b"ram"),
+            get_entry("flash/", EntryType::Directory, 0, &[]),
+            get_file("flash/pmas", &[7u8; 600]),
+        ]);
+
+        let output_path =
+            Path::new(&unpack("files", tar, 1024).unwrap()).to_path_buf();
+        assert_eq!(std::fs::read(output_path.join("config")).unwrap(), b"ram");
+        let pmas = std::fs::read(output_path.join("flash/pmas")).unwrap();
+        assert_eq!(pmas, vec![7u8; 600]);
+    }
+
+    #[test]
+    fn strips_a_single_root_directory() {
+        let tar = get_archive(vec![
+            get_entry("./machine/", EntryType::Directory, 0, &[]),
+            get_file("./machine/config", b"ram"),
+            get_file("./machine/flash/pmas", &[7u8; 600]),
+        ]);
+
+        let output_path =
+            Path::new(&unpack("root", tar, 1024).unwrap()).to_path_buf();
+        assert_eq!(std::fs::read(output_path.join("config")).unwrap(), b"ram");
+        let pmas = std::fs::read(output_path.join("flash/pmas")).unwrap();
+        assert_eq!(pmas, vec![7u8; 600]);
+        assert!(!output_path.join("machine").exists());
+
+        // two top-level directories are left as they are
+        let tar = get_archive(vec![
+            get_file("a/config", b"ram"),
+            get_file("b/config", b"ram"),
+        ]);
+        let output_path =
+            Path::new(&unpack("roots", tar, 1024).unwrap()).to_path_buf();
+        assert!(output_path.join("a/config").is_file());
+        assert!(output_path.join("b/config").is_file());
+    }
+
+    #[test]
+    fn rejects_oversized_entries() {
+        let tar = get_archive(vec![get_file("a", &[1u8; 600])]);
+        assert!(unpack("one-oversized", tar, 599).is_err());
+        let tar = get_archive(vec![
+            get_file("a", &[1u8; 600]),
+            get_file("b", &[1u8; 600]),
+        ]);
+        assert!(unpack("all-oversized", tar, 1000).is_err());
+
+        // the size is checked before any content is read
+        let huge = get_entry("huge", EntryType::Regular, 1 << 40, &[]);
+        assert!(unpack("huge", huge, 1 << 30).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_archives() {
+        let mut tar = get_file("a", &[1u8; 600]);
+        tar.truncate(BLOCK_SIZE + 100);
+        assert!(unpack("truncated-content", tar, 1024).is_err());
+
+        let tar = get_file("a", b"data")[..100].to_vec();
+        assert!(unpack("truncated-header", tar, 1024).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let mut tar = get_archive(vec![get_file("a", b"data")]);
+        tar[0] = b'b';
+        assert!(unpack("checksum", tar, 1024).is_err());
+
+        let mut tar = get_archive(vec![get_file("a", b"data")]);
+        tar[124..135].copy_from_slice(b"0000000000x");
+        let mut header = tar::Header::new_old();
+        header.as_mut_bytes().copy_from_slice(&tar[..BLOCK_SIZE]);
+        header.set_cksum();
+        tar[..BLOCK_SIZE].copy_from_slice(header.as_bytes());
+        assert!(unpack("size", tar, 1024).is_err());
+    }
+
+    #[test]
+    fn rejects_unsafe_and_unsupported_entries() {
+        let tar = get_archive(vec![get_file("../escape", b"x")]);
+        assert!(unpack("parent", tar, 1024).is_err());
+        let tar = get_archive(vec![get_file("/escape", b"x")]);
+        assert!(unpack("absolute", tar, 1024).is_err());
+        let tar = get_archive(vec![get_file("machine/../../escape", b"x")]);
+        assert!(unpack("nested-parent", tar, 1024).is_err());
+        let link =
+            get_archive(vec![get_entry("link", EntryType::Symlink, 0, &[])]);
+        assert!(unpack("link", link, 1024).is_err());
+        let dir = get_archive(vec![get_entry(
+            "dir/",
+            EntryType::Directory,
+            4,
+            b"data",
+        )]);
+        assert!(unpack("dir", dir, 1024).is_err());
+    }
+}
@@ -869,6 +869,36 @@ contract CartesiCompute is
     /// @notice Reveal the content of a logger drive (only drive provider can call it).
     /// @param _index index of Cartesi Compute instance the drive belongs to
     function revealLoggerDrive(uint256 _index) public onlyActive(_index) {
+        _revealLoggerDrive(_index);
+    }
+
+    /// @notice Reveal the content of a logger drive, also publishing the ipfs
+    ///         path where it can be downloaded from (only drive provider can
+    ///         call it, and only if the drive has no ipfs path yet).
+    /// @param _index index of Cartesi Compute instance the drive belongs to
+    /// @param _ipfsPath ipfs object path of the logger drive
+    function revealLoggerDriveWithIpfsPath(
+        uint256 _index,
+        bytes memory _ipfsPath
+    ) public onlyActive(_index) {
+        CartesiComputeCtx storage i = instance[_index];
+        require(
+            i.currentState == State.WaitingReveals,
+            "State != WaitingReveals"
+        );
+
+        uint256 driveIndex = i.revealDrives[i.revealDrivesPointer];
+        require(driveIndex < i.inputDrives.length, "Invalid driveIndex");
+
+        Drive storage drive = i.inputDrives[driveIndex];
+        require(drive.provider == msg.sender, "Sender != provider");
+        require(drive.loggerIpfsPath.length == 0, "Ipfs path already set");
+
+        drive.loggerIpfsPath = _ipfsPath;
+        _revealLoggerDrive(_index);
+    }
+
+    function _revealLoggerDrive(uint256 _index) internal {
         CartesiComputeCtx storage i = instance[_index];
         require(
             i.currentState == State.WaitingReveals,
@@ -398,7 +398,7 @@ describe("Cartesi Compute tests", () => {
         { ...aDrive, directValue: "0x" + "00".repeat(7) },
         { ...aDrive, waitsProvider: true },
         { ...aDrive, needsLogger: true, waitsProvider: true },
-        { ...aDrive, needsLogger: true, waitsProvider: false }, // a reveal drive
+        { ...aDrive, needsLogger: true, waitsProvider: false, loggerIpfsPath: "0x" }, // a reveal drive
       ];
       await mockLogger.mock.isLogAvailable.returns(false);
       const tx = cartesi_compute.instantiate(
@@ -527,6 +527,65 @@ describe("Cartesi Compute tests", () => {
       await revertSnapshot();
     });
 
+    it("Should revealLoggerDriveWithIpfsPath", async () => {
+      const revertSnapshot = await takeSnapshot();
+      const ipfsPath = ethers.utils.hexlify(
+        ethers.utils.toUtf8Bytes("/ipfs/QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG")
+      );
+
+      await mockLogger.mock.isLogAvailable.returns(true);
+      let tx = cartesi_compute.revealLoggerDriveWithIpfsPath(cartesi_computeIdx, ipfsPath);
+      await expect(tx).to.be.revertedWith("Sender != provider");
+
+      tx = cartesi_compute
+        .connect(claimer)
+        .revealLoggerDriveWithIpfsPath(cartesi_computeIdx, ipfsPath);
+      await expect(tx).not.to.be.reverted;
+
+      const tx2 = await cartesi_compute.getState(cartesi_computeIdx, mainSignerAddress);
+      expect(tx2[4][3].loggerIpfsPath).to.equal(ipfsPath);
+      expect(await cartesi_compute.getCurrentState(cartesi_computeIdx)).to.be.equal(
+        ethers.utils.formatBytes32String("WaitingClaim")
+      );
+
+      await revertSnapshot();
+    });
+
+    it("Should fail to revealLoggerDriveWithIpfsPath over an existing path", async () => {
+      const revertSnapshot = await takeSnapshot();
+      const drives = [{ ...aDrive, needsLogger: true }];
+      const ipfsPath = ethers.utils.hexlify(
+        ethers.utils.toUtf8Bytes("/ipfs/QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG")
+      );
+
+      await mockLogger.mock.isLogAvailable.returns(false);
+      const txResult = await (
+        await cartesi_compute.instantiate(
+          finalTime,
+          templateHash,
+          outputPosition,
+          outputLog2Size,
+          roundDuration,
+          [claimerAddress, challengerAddress],
+          drives,
+          false
+        )
+      ).wait();
+      const idx = ethers.BigNumber.from(txResult.logs[0].data).toNumber();
+      await cartesi_compute.connect(claimer).challengeDrives(idx);
+      expect(await cartesi_compute.getCurrentState(idx)).to.be.equal(
+        ethers.utils.formatBytes32String("WaitingReveals")
+      );
+
+      await mockLogger.mock.isLogAvailable.returns(true);
+      const tx = cartesi_compute
+        .connect(claimer)
+        .revealLoggerDriveWithIpfsPath(idx, ipfsPath);
+      await expect(tx).to.be.revertedWith("Ipfs path already set");
+
+      await revertSnapshot();
+    });
+
     it("Should revealLoggerDrive", async () => {
       await mockLogger.mock.isLogAvailable.returns(false);
       let tx = cartesi_compute.revealLoggerDrive(cartesi_computeIdx);